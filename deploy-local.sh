@@ -2,7 +2,7 @@
 #!/bin/sh
 
 cargo test
-dfx deploy john_dao -m reinstall -y
+dfx deploy john_dao -m reinstall -y --argument "(variant { Init = record { admins = vec { principal \"$(dfx identity get-principal)\" }; settings = null; ledger_canister_id = null; reward_token = null; staking_ledger_canister_id = null } })"
 # dfx deploy internet_identity -y
 cd ./src/frontend
 dfx generate
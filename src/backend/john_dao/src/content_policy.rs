@@ -0,0 +1,338 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use candid::CandidType;
+use ic_stable_structures::{storable::Bound, Storable};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{decode, encode},
+    Error,
+};
+
+// upper bound on the compiled size of an admin supplied pattern
+const MAX_PATTERN_SIZE_BYTES: usize = 1 << 20;
+
+/// What proposed tweets may contain, managed by the admins.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct ContentPolicy {
+    // words and phrases that may not appear, matched case-insensitively on whole words
+    pub banned_words: Vec<String>,
+    // regular expressions that may not match anywhere in the tweet
+    pub banned_patterns: Vec<String>,
+    // when not empty, links may only point to these domains and their subdomains
+    pub allowed_domains: Vec<String>,
+    pub denied_domains: Vec<String>,
+    // tweets at least this similar to a current proposal or a past winner are rejected,
+    // 0 disables the check
+    pub max_similarity_percent: u8,
+}
+
+impl Default for ContentPolicy {
+    fn default() -> Self {
+        ContentPolicy {
+            banned_words: Vec::new(),
+            banned_patterns: Vec::new(),
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            max_similarity_percent: 90,
+        }
+    }
+}
+
+/// One step of the pipeline every proposed tweet has to pass.
+pub trait ContentCheck {
+    fn check(&self, tweet: &str) -> Result<(), Error>;
+}
+
+impl<T: ContentCheck + ?Sized> ContentCheck for &T {
+    fn check(&self, tweet: &str) -> Result<(), Error> {
+        (**self).check(tweet)
+    }
+}
+
+/// Runs the checks in order and returns the first rejection.
+pub fn check_content(checks: &[Box<dyn ContentCheck + '_>], tweet: &str) -> Result<(), Error> {
+    checks.iter().try_for_each(|check| check.check(tweet))
+}
+
+/// Lowercase words separated by single spaces.
+fn normalize(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub struct BannedWords {
+    // normalized, padded with spaces so only whole words match
+    words: Vec<(String, String)>,
+}
+
+impl BannedWords {
+    pub fn new(words: &[String]) -> Self {
+        let words = words
+            .iter()
+            .map(|word| (word.clone(), format!(" {} ", normalize(word))))
+            .filter(|(_, normalized)| normalized.trim() != "")
+            .collect();
+        Self { words }
+    }
+}
+
+impl ContentCheck for BannedWords {
+    fn check(&self, tweet: &str) -> Result<(), Error> {
+        let tweet = format!(" {} ", normalize(tweet));
+        match self.words.iter().find(|(_, word)| tweet.contains(word)) {
+            Some((word, _)) => Err(Error::ContainsBannedWord(word.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BannedPatterns {
+    patterns: Vec<Regex>,
+}
+
+impl BannedPatterns {
+    /// Fails with `Error::InvalidPattern` if one of the patterns does not compile.
+    pub fn compile(patterns: &[String]) -> Result<Self, Error> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .size_limit(MAX_PATTERN_SIZE_BYTES)
+                    .build()
+                    .map_err(|e| Error::InvalidPattern(e.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+}
+
+impl ContentCheck for BannedPatterns {
+    fn check(&self, tweet: &str) -> Result<(), Error> {
+        match self.patterns.iter().find(|pattern| pattern.is_match(tweet)) {
+            Some(pattern) => Err(Error::MatchesBannedPattern(pattern.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+thread_local! {
+    // only text with a scheme or a `www.` prefix is a link, so "Node.js" or "file.rs" are not
+    static LINK: Regex = Regex::new(
+        r"(?i)\b(?:https?://((?:[a-z0-9-]+\.)+[a-z]{2,})|(www\.(?:[a-z0-9-]+\.)+[a-z]{2,}))\b",
+    )
+    .expect("link pattern is valid");
+}
+
+/// Domains of the links in `text`, starting with a scheme or `www.`.
+fn linked_domains(text: &str) -> Vec<String> {
+    LINK.with(|link| {
+        link.captures_iter(text)
+            .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+            .map(|domain| domain.as_str().to_lowercase())
+            .collect()
+    })
+}
+
+fn matches_domain(domain: &str, listed: &str) -> bool {
+    let listed = listed.trim().trim_start_matches('.').to_lowercase();
+    domain == listed || domain.ends_with(&format!(".{}", listed))
+}
+
+pub struct LinkPolicy {
+    allowed_domains: Vec<String>,
+    denied_domains: Vec<String>,
+}
+
+impl LinkPolicy {
+    pub fn new(allowed_domains: &[String], denied_domains: &[String]) -> Self {
+        Self {
+            allowed_domains: allowed_domains.to_vec(),
+            denied_domains: denied_domains.to_vec(),
+        }
+    }
+}
+
+impl ContentCheck for LinkPolicy {
+    fn check(&self, tweet: &str) -> Result<(), Error> {
+        for domain in linked_domains(tweet) {
+            if self
+                .denied_domains
+                .iter()
+                .any(|denied| matches_domain(&domain, denied))
+            {
+                return Err(Error::DomainDenied(domain));
+            }
+            if !self.allowed_domains.is_empty()
+                && !self
+                    .allowed_domains
+                    .iter()
+                    .any(|allowed| matches_domain(&domain, allowed))
+            {
+                return Err(Error::DomainNotAllowed(domain));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Character trigrams of the normalized text, the whole text if it is shorter and nothing
+/// if it has no words.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Trigrams(HashSet<String>);
+
+impl Trigrams {
+    pub fn of(text: &str) -> Self {
+        let chars = normalize(text).chars().collect::<Vec<_>>();
+        if chars.is_empty() {
+            return Self(HashSet::new());
+        }
+        if chars.len() < 3 {
+            return Self(HashSet::from([chars.into_iter().collect()]));
+        }
+        Self(
+            chars
+                .windows(3)
+                .map(|window| window.iter().collect())
+                .collect(),
+        )
+    }
+
+    /// Jaccard similarity of the trigrams of two texts, in percent.
+    fn similarity_percent(&self, other: &Trigrams) -> u8 {
+        let union = self.0.union(&other.0).count().max(1);
+        (self.0.intersection(&other.0).count() * 100 / union) as u8
+    }
+}
+
+impl Storable for Trigrams {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub enum Earlier {
+    Proposal(u32),
+    RoundWinner(u32),
+}
+
+pub struct NearDuplicates<'a> {
+    earlier: Vec<(Earlier, Cow<'a, Trigrams>)>,
+    max_similarity_percent: u8,
+}
+
+impl<'a> NearDuplicates<'a> {
+    pub fn new(earlier: Vec<(Earlier, Cow<'a, Trigrams>)>, max_similarity_percent: u8) -> Self {
+        Self {
+            earlier,
+            max_similarity_percent,
+        }
+    }
+}
+
+impl ContentCheck for NearDuplicates<'_> {
+    fn check(&self, tweet: &str) -> Result<(), Error> {
+        let tweet = Trigrams::of(tweet);
+        // tweets without words, like image only ones, are never duplicates
+        if tweet.0.is_empty() {
+            return Ok(());
+        }
+        let duplicate = self
+            .earlier
+            .iter()
+            .find(|(_, earlier)| tweet.similarity_percent(earlier) >= self.max_similarity_percent);
+        match duplicate {
+            Some((Earlier::Proposal(proposal_id), _)) => {
+                Err(Error::DuplicateOfProposal(*proposal_id))
+            }
+            Some((Earlier::RoundWinner(round_id), _)) => {
+                Err(Error::DuplicateOfRoundWinner(*round_id))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn rejects_banned_words_on_whole_words() {
+        let check = BannedWords::new(&strings(&["scam", "free money"]));
+        assert!(check.check("Not a scammer").is_ok());
+        assert!(matches!(
+            check.check("This is a SCAM!"),
+            Err(Error::ContainsBannedWord(word)) if word == "scam"
+        ));
+        assert!(check.check("get FREE   money now").is_err());
+    }
+
+    #[test]
+    fn checks_linked_domains() {
+        let check = LinkPolicy::new(&strings(&["twitter.com"]), &strings(&["bad.twitter.com"]));
+        assert!(check
+            .check("see https://twitter.com/x and www.mobile.twitter.com")
+            .is_ok());
+        assert!(matches!(
+            check.check("go to https://Bad.Twitter.com/claim"),
+            Err(Error::DomainDenied(domain)) if domain == "bad.twitter.com"
+        ));
+        assert!(matches!(
+            check.check("claim at www.scam.io"),
+            Err(Error::DomainNotAllowed(domain)) if domain == "www.scam.io"
+        ));
+    }
+
+    #[test]
+    fn ignores_dotted_words_that_are_not_links() {
+        let check = LinkPolicy::new(&strings(&["twitter.com"]), &[]);
+        assert!(check
+            .check("Built with Node.js and Vue.js, see main.rs. Ok.Thanks")
+            .is_ok());
+        assert!(check.check("visit http://scam.io today").is_err());
+    }
+
+    #[test]
+    fn rejects_near_duplicates() {
+        let check = NearDuplicates::new(
+            vec![
+                (
+                    Earlier::Proposal(4),
+                    Cow::Owned(Trigrams::of("The DAO should fund more public goods")),
+                ),
+                (
+                    Earlier::RoundWinner(2),
+                    Cow::Owned(Trigrams::of("gm everyone")),
+                ),
+            ],
+            90,
+        );
+        assert!(matches!(
+            check.check("the dao should fund more public goods!!"),
+            Err(Error::DuplicateOfProposal(4))
+        ));
+        assert!(matches!(
+            check.check("GM, everyone"),
+            Err(Error::DuplicateOfRoundWinner(2))
+        ));
+        assert!(check
+            .check("The DAO should fund fewer private goods")
+            .is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Principal};
+use ic_ledger_types::{AccountIdentifier, Block, BlockIndex, GetBlocksArgs};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{decode, encode, get_donations_memory, get_donor_totals_memory, Memory},
+    Error,
+};
+
+/// An ICP transfer into the canister's default account that a user notified us about.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Donation {
+    pub block_index: BlockIndex,
+    pub from: AccountIdentifier,
+    pub user_id: u32,
+    pub amount_e8s: u64,
+    // time of the ledger block
+    pub time: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct DonorTotal {
+    pub total_e8s: u64,
+    pub nr_of_donations: u32,
+    pub last_donation: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Donor {
+    pub user_id: u32,
+    pub user_name: Option<String>,
+    pub total: DonorTotal,
+}
+
+impl Storable for Donation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for DonorTotal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Fetches a single block from the ICP ledger, following it into the archive if needed.
+pub async fn query_block(
+    ledger_canister_id: Principal,
+    block_index: BlockIndex,
+) -> Result<Option<Block>, Error> {
+    let args = GetBlocksArgs {
+        start: block_index,
+        length: 1,
+    };
+    let response = ic_ledger_types::query_blocks(ledger_canister_id, args.clone())
+        .await
+        .map_err(|(_, e)| Error::PaymentError(e))?;
+    if let Some(block) = response.blocks.into_iter().next() {
+        return Ok(Some(block));
+    }
+
+    let archive = response
+        .archived_blocks
+        .into_iter()
+        .find(|range| range.start <= block_index && block_index - range.start < range.length);
+    let Some(archive) = archive else {
+        return Ok(None);
+    };
+    ic_ledger_types::query_archived_blocks(&archive.callback, args)
+        .await
+        .map_err(|(_, e)| Error::PaymentError(e))?
+        .map(|range| range.blocks.into_iter().next())
+        .map_err(|e| Error::PaymentError(e.to_string()))
+}
+
+/// Donations by ledger block index, so a block can only be notified once, and the
+/// running total of every donor.
+pub struct Donations {
+    donations: StableBTreeMap<BlockIndex, Donation, Memory>,
+    totals: StableBTreeMap<u32, DonorTotal, Memory>,
+}
+
+impl Donations {
+    pub fn new() -> Self {
+        Self {
+            donations: StableBTreeMap::init(get_donations_memory()),
+            totals: StableBTreeMap::init(get_donor_totals_memory()),
+        }
+    }
+
+    pub fn contains(&self, block_index: BlockIndex) -> bool {
+        self.donations.contains_key(&block_index)
+    }
+
+    pub fn record(&mut self, donation: Donation) -> DonorTotal {
+        let mut total = self.totals.get(&donation.user_id).unwrap_or_default();
+        total.total_e8s += donation.amount_e8s;
+        total.nr_of_donations += 1;
+        total.last_donation = total.last_donation.max(donation.time);
+        self.totals.insert(donation.user_id, total.clone());
+        self.donations.insert(donation.block_index, donation);
+        total
+    }
+
+    /// Donors ordered by the total they donated, largest first.
+    pub fn get_top_donors(&self, nr_of_donors: u32) -> Vec<(u32, DonorTotal)> {
+        let mut donors = self.totals.iter().collect::<Vec<_>>();
+        donors.sort_by_key(|(_, total)| std::cmp::Reverse(total.total_e8s));
+        donors.truncate(nr_of_donors as usize);
+        donors
+    }
+}
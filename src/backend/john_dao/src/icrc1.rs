@@ -0,0 +1,61 @@
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+// types of the ICRC-1 ledger interface, see https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-1/ICRC-1.did
+
+pub type Subaccount = [u8; 32];
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+pub async fn transfer(
+    ledger_canister_id: Principal,
+    arg: TransferArg,
+) -> CallResult<Result<Nat, TransferError>> {
+    ic_cdk::call::<_, (Result<Nat, TransferError>,)>(ledger_canister_id, "icrc1_transfer", (arg,))
+        .await
+        .map(|(result,)| result)
+}
+
+pub async fn balance_of(ledger_canister_id: Principal, account: Account) -> CallResult<Nat> {
+    ic_cdk::call::<_, (Nat,)>(ledger_canister_id, "icrc1_balance_of", (account,))
+        .await
+        .map(|(balance,)| balance)
+}
+
+pub async fn fee(ledger_canister_id: Principal) -> CallResult<Nat> {
+    ic_cdk::call::<_, (Nat,)>(ledger_canister_id, "icrc1_fee", ())
+        .await
+        .map(|(fee,)| fee)
+}
+
+pub fn nat_to_u64(nat: &Nat) -> u64 {
+    nat.0.to_u64().unwrap_or(u64::MAX)
+}
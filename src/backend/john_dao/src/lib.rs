@@ -1,33 +1,61 @@
 use std::{
+    borrow::Cow,
     cell::RefCell,
-    collections::{HashMap, HashSet},
-    ops::Deref,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     time::Duration,
 };
 
-use candid::{candid_method, CandidType, Deserialize, Principal};
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use content_policy::{
+    BannedPatterns, BannedWords, ContentCheck, ContentPolicy, Earlier, LinkPolicy, NearDuplicates,
+    Trigrams,
+};
+use donations::{Donation, Donations, Donor, DonorTotal};
 use ic_cdk::{
-    api::{
-        call::RejectionCode,
-        stable::{StableReader, StableWriter},
-        time,
-    },
+    api::{stable::StableReader, time},
     caller, id, init, query, update,
 };
 use ic_cdk_macros::{post_upgrade, pre_upgrade};
+use ic_cdk_timers::TimerId;
 use ic_ledger_types::{
-    AccountIdentifier, BlockIndex, Memo, Tokens, TransferArgs, TransferError, DEFAULT_FEE,
-    DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID,
+    AccountBalanceArgs, AccountIdentifier, Block, BlockIndex, Operation, DEFAULT_SUBACCOUNT,
+    MAINNET_LEDGER_CANISTER_ID,
+};
+use ic_stable_structures::{
+    reader::Reader, storable::Bound, writer::Writer, StableBTreeMap, StableLog, Storable,
 };
+use icrc1::Account;
+use memory::{decode, encode, Memory};
+use migrations::{StateV0, StateVersion, CURRENT_VERSION};
+use moderation::{ModerationStatus, ReportReason, ReportWeighting, ORDINARY_REPORT_WEIGHT};
+use payments::{PaymentTarget, PendingPayment, TransferOutcome};
+use proposal_index::{ProposalFilter, ProposalIndex, ProposalSort};
+use rewards::{Credit, CreditReason, Earning, Earnings, RewardCurve};
+use roles::{Role, Roles};
 use serde::Serialize;
-use users::{LoginMethod, User, Users, VerificationStatus};
+use staking::{Stake, Stakes, UnstakeWithdrawal};
+use treasury::{Treasury, TreasuryStatus};
+use users::{LoginMethod, UnpaidCredit, User, Users, VerificationStatus, VoteWeight};
 
 use crate::users::Badge;
 
+mod content_policy;
+mod donations;
+mod icrc1;
+mod memory;
+mod migrations;
+mod moderation;
+mod payments;
+mod proposal_index;
+mod rewards;
+mod roles;
+mod staking;
+mod treasury;
 mod users;
 
 thread_local! {
-    static STATE: RefCell<Option<State>> = RefCell::new(None);
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+    static ROUND_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
 }
 
 #[derive(CandidType, Deserialize)]
@@ -35,6 +63,31 @@ struct CreateUserArg {
     pub principal: Principal,
 }
 
+#[derive(CandidType, Deserialize)]
+enum JohnDaoArgs {
+    Init(InitArgs),
+    Upgrade(UpgradeArgs),
+}
+
+#[derive(CandidType, Deserialize)]
+struct InitArgs {
+    pub settings: Option<Settings>,
+    pub admins: Vec<Principal>,
+    pub ledger_canister_id: Option<Principal>,
+    pub reward_token: Option<RewardToken>,
+    pub staking_ledger_canister_id: Option<Principal>,
+}
+
+// every field left empty keeps its current value
+#[derive(CandidType, Deserialize)]
+struct UpgradeArgs {
+    pub settings: Option<Settings>,
+    pub admins: Option<Vec<Principal>>,
+    pub ledger_canister_id: Option<Principal>,
+    pub reward_token: Option<RewardToken>,
+    pub staking_ledger_canister_id: Option<Principal>,
+}
+
 struct Access;
 impl Access {
     fn with_state<R>(f: impl FnOnce(&State) -> R) -> R {
@@ -73,6 +126,34 @@ struct Proposal {
     pub created_by_id: u32,
     pub created_at: u64,
     pub reports: HashSet<Principal>,
+    // number of rounds the proposal was carried over from
+    #[serde(default)]
+    pub rounds_survived: u32,
+    // number of reports for each reason, reports made before reasons existed are missing
+    #[serde(default)]
+    pub report_reasons: HashMap<ReportReason, u32>,
+    #[serde(default)]
+    pub moderation_status: ModerationStatus,
+    // sum of the weights of all reports, missing for proposals reported before reports
+    // were weighted
+    #[serde(default)]
+    pub weighted_reports: Option<u32>,
+    // reporters a moderator decision was already counted for
+    #[serde(default)]
+    pub judged_reports: HashSet<Principal>,
+    // distinct users that up- and downvoted it this round
+    #[serde(default)]
+    pub nr_of_upvotes: u32,
+    #[serde(default)]
+    pub nr_of_downvotes: u32,
+}
+
+impl Proposal {
+    /// Sum of the report weights, unweighted reports count as ordinary ones.
+    fn report_weight(&self) -> u32 {
+        self.weighted_reports
+            .unwrap_or(self.reports.len() as u32 * ORDINARY_REPORT_WEIGHT)
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
@@ -83,8 +164,11 @@ struct ProposalInfo {
     pub created_by: u32,
     pub created_at: u64,
     pub upvote_status: UpvoteStatus,
+    // what the caller's vote counts for, 0 if they did not vote
+    pub vote_weight: i32,
     pub nr_of_reports: u32,
     pub reported: bool,
+    pub rounds_survived: u32,
 }
 
 impl ProposalInfo {
@@ -92,6 +176,7 @@ impl ProposalInfo {
         proposal: &Proposal,
         id: u32,
         upvote_status: UpvoteStatus,
+        vote_weight: i32,
         caller: Principal,
     ) -> Self {
         ProposalInfo {
@@ -103,6 +188,8 @@ impl ProposalInfo {
             nr_of_reports: proposal.reports.len() as u32,
             reported: proposal.reports.contains(&caller),
             upvote_status,
+            vote_weight,
+            rounds_survived: proposal.rounds_survived,
         }
     }
 }
@@ -122,6 +209,212 @@ struct Settings {
     pub max_proposals_per_round: u32,
     pub max_proposals_per_user: u32,
     pub max_tweet_length: u32,
+    #[serde(default)]
+    pub reward_token: RewardToken,
+    #[serde(default = "default_dissolve_delay_seconds")]
+    pub dissolve_delay_seconds: u64,
+    // every this many staked e8s add one to a user's voting power, 0 disables stake weighting
+    #[serde(default = "default_stake_e8s_per_extra_vote")]
+    pub stake_e8s_per_extra_vote: u64,
+    #[serde(default)]
+    pub fee_policy: FeePolicy,
+    // smallest amount a user can withdraw at once, before the fee is subtracted
+    #[serde(default)]
+    pub min_withdrawal_e8s: u64,
+    // share of the round reward that goes to the author of the winning proposal, the
+    // voters split the rest
+    #[serde(default = "default_author_reward_percent")]
+    pub author_reward_percent: u8,
+    #[serde(default)]
+    pub voting_mode: VotingMode,
+    #[serde(default)]
+    pub verification_policy: VerificationPolicy,
+    #[serde(default)]
+    pub reward_curve: RewardCurve,
+    #[serde(default = "default_winners_per_round")]
+    pub winners_per_round: u32,
+    // a proposal needs at least these points and this many distinct voters to win
+    #[serde(default = "default_min_winning_points")]
+    pub min_winning_points: i32,
+    #[serde(default = "default_min_distinct_voters")]
+    pub min_distinct_voters: u32,
+    #[serde(default)]
+    pub carry_over_policy: CarryOverPolicy,
+    // a proposal with this many reports is hidden until a moderator reviews it, 0 disables hiding
+    #[serde(default = "default_report_threshold")]
+    pub report_threshold: u32,
+    // karma the author loses when a moderator removes their proposal
+    #[serde(default = "default_removal_karma_penalty")]
+    pub removal_karma_penalty: i32,
+    #[serde(default = "default_max_reports_per_round")]
+    pub max_reports_per_round: u32,
+    #[serde(default)]
+    pub report_weighting: ReportWeighting,
+}
+
+// verification statuses that may take part in each activity
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct VerificationPolicy {
+    pub can_propose: Vec<VerificationStatus>,
+    pub can_vote: Vec<VerificationStatus>,
+    pub can_receive_rewards: Vec<VerificationStatus>,
+}
+
+impl Default for VerificationPolicy {
+    fn default() -> Self {
+        let humans = vec![VerificationStatus::Unverified, VerificationStatus::Verified];
+        VerificationPolicy {
+            can_propose: humans.clone(),
+            can_vote: humans.clone(),
+            can_receive_rewards: humans,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+enum VotingMode {
+    // every vote counts once, plus the extra votes from staking
+    #[default]
+    OnePersonOneVote,
+    // one extra vote for every `karma_per_extra_vote` karma, up to `max_extra_votes`
+    KarmaWeighted {
+        karma_per_extra_vote: u32,
+        max_extra_votes: u32,
+    },
+    // like OnePersonOneVote but only verified users can vote
+    VerifiedOnly,
+    // users pick how many votes to cast on a proposal, n votes cost n * n of the credits
+    // they get every round. Staking does not add votes in this mode.
+    Quadratic {
+        credits_per_round: u64,
+    },
+}
+
+fn default_dissolve_delay_seconds() -> u64 {
+    3600 * 24 * 7
+}
+
+fn default_stake_e8s_per_extra_vote() -> u64 {
+    100_000_000
+}
+
+fn default_author_reward_percent() -> u8 {
+    30
+}
+
+fn default_winners_per_round() -> u32 {
+    1
+}
+
+fn default_min_winning_points() -> i32 {
+    1
+}
+
+fn default_min_distinct_voters() -> u32 {
+    1
+}
+
+fn default_report_threshold() -> u32 {
+    5
+}
+
+fn default_removal_karma_penalty() -> i32 {
+    10
+}
+
+fn default_max_reports_per_round() -> u32 {
+    10
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            round_duration_seconds: 60, //3600 * 12;
+            max_state_size_bytes: 1_000_000_000,
+            reward_per_round_e8s: 100_000_000, //1 icp 100_000_000
+            max_proposals_per_round: 1500,
+            max_proposals_per_user: 5,
+            max_tweet_length: 280,
+            reward_token: RewardToken::Icp,
+            dissolve_delay_seconds: default_dissolve_delay_seconds(),
+            stake_e8s_per_extra_vote: default_stake_e8s_per_extra_vote(),
+            fee_policy: FeePolicy::UserPays,
+            min_withdrawal_e8s: 0,
+            author_reward_percent: default_author_reward_percent(),
+            voting_mode: VotingMode::OnePersonOneVote,
+            verification_policy: VerificationPolicy::default(),
+            reward_curve: RewardCurve::default(),
+            winners_per_round: default_winners_per_round(),
+            min_winning_points: default_min_winning_points(),
+            min_distinct_voters: default_min_distinct_voters(),
+            carry_over_policy: CarryOverPolicy::ResetToOne,
+            report_threshold: default_report_threshold(),
+            removal_karma_penalty: default_removal_karma_penalty(),
+            max_reports_per_round: default_max_reports_per_round(),
+            report_weighting: ReportWeighting::default(),
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+enum RewardToken {
+    #[default]
+    Icp,
+    Icrc1 {
+        ledger_canister_id: Principal,
+    },
+}
+
+// who pays the ledger fee of a reward withdrawal
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+enum FeePolicy {
+    // the fee is subtracted from the withdrawn amount
+    #[default]
+    UserPays,
+    // the user receives the full amount and the canister pays the fee on top
+    TreasuryPays,
+}
+
+// what happens to the proposals that did not win when a round ends, proposals that end up
+// with fewer than 1 point are always removed
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+enum CarryOverPolicy {
+    // every proposal is removed
+    None,
+    // proposals start the next round with 1 point
+    #[default]
+    ResetToOne,
+    // proposals keep their points
+    Full,
+    // proposals keep this percentage of their points, rounded down
+    Decay {
+        percent_kept: u8,
+    },
+    // proposals keep their points until they survived this many rounds
+    MaxAge {
+        max_rounds: u32,
+    },
+}
+
+impl CarryOverPolicy {
+    /// Points a proposal starts the next round with, `None` if it is removed.
+    fn carried_points(&self, points: i32, rounds_survived: u32) -> Option<i32> {
+        let points = match *self {
+            CarryOverPolicy::None => return None,
+            CarryOverPolicy::ResetToOne => points.min(1),
+            CarryOverPolicy::Full => points,
+            CarryOverPolicy::Decay { percent_kept } => {
+                (i64::from(points) * i64::from(percent_kept.min(100)) / 100) as i32
+            }
+            CarryOverPolicy::MaxAge { max_rounds } if rounds_survived < max_rounds => points,
+            CarryOverPolicy::MaxAge { .. } => return None,
+        };
+        (points >= 1).then_some(points)
+    }
+}
+
+fn mainnet_ledger_canister_id() -> Principal {
+    MAINNET_LEDGER_CANISTER_ID
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
@@ -129,27 +422,195 @@ struct Payment {
     pub block_index: BlockIndex,
     pub user_id: u32,
     pub time: u64,
+    #[serde(default)]
+    pub token: RewardToken,
+    // rounds whose rewards this payment paid out, oldest first
+    #[serde(default)]
+    pub round_ids: Vec<u32>,
 }
 
-#[derive(CandidType, Serialize, Deserialize)]
+// users, proposals, round results and payments live in stable memory and are not
+// part of the state that gets serialized on upgrade
+#[derive(Serialize, Deserialize)]
 struct State {
+    #[serde(default)]
+    pub version: StateVersion,
     pub next_user_id: u32,
+    #[serde(skip, default = "Users::new")]
     pub users: Users,
     pub next_proposal_id: u32,
-    pub proposals: HashMap<u32, Proposal>,
-    pub round_results: Vec<RoundResult>,
-    pub payment_history: Vec<Payment>,
+    #[serde(skip, default = "init_proposals")]
+    pub proposals: StableBTreeMap<u32, Proposal, Memory>,
+    #[serde(skip, default = "init_round_results")]
+    pub round_results: StableBTreeMap<u32, RoundResult, Memory>,
+    // how every proposal finished its round, by round id and rank
+    #[serde(skip, default = "init_standings")]
+    pub standings: StableBTreeMap<(u32, u32), ProposalStanding, Memory>,
+    // rounds with a winner that was not tweeted yet
+    #[serde(default)]
+    pub untweeted_rounds: BTreeSet<u32>,
+    #[serde(skip, default = "init_payment_history")]
+    pub payment_history: StableLog<Payment, Memory, Memory>,
     pub settings: Settings,
     pub round_end_time: u64,
     pub last_user_backup: u64,
-    pub test: Option<String>,
+    pub roles: Roles,
+    #[serde(default = "mainnet_ledger_canister_id")]
+    pub ledger_canister_id: Principal,
+    #[serde(skip, default = "Stakes::new")]
+    pub stakes: Stakes,
+    #[serde(default)]
+    pub staking_ledger_canister_id: Option<Principal>,
+    #[serde(default)]
+    pub next_payment_id: u64,
+    #[serde(default)]
+    pub pending_payments: BTreeMap<u64, PendingPayment>,
+    #[serde(default)]
+    pub treasury: Treasury,
+    #[serde(skip, default = "Donations::new")]
+    pub donations: Donations,
+    #[serde(skip, default = "Earnings::new")]
+    pub earnings: Earnings,
+    // e8s of earlier rounds that could not be split evenly, added to the next round's reward
+    #[serde(default)]
+    pub reward_carry_over_e8s: u64,
+    // rebuilt from the proposals after every upgrade
+    #[serde(skip)]
+    pub proposal_index: ProposalIndex,
+    #[serde(default)]
+    pub content_policy: ContentPolicy,
+    // compiled from `content_policy`, recompiled after every upgrade
+    #[serde(skip)]
+    pub banned_patterns: BannedPatterns,
+    // trigrams of the tweets of past round winners, by round id and rank
+    #[serde(skip, default = "init_winner_trigrams")]
+    pub winner_trigrams: StableBTreeMap<(u32, u32), Trigrams, Memory>,
 }
 
 #[derive(Clone, CandidType, Serialize, Deserialize)]
 struct RoundResult {
     pub round_id: u32,
-    pub winning_proposal: Proposal,
+    // best ranked winner, kept next to `winners` for clients that only know one winner,
+    // none if no proposal reached the minimum points and voters
+    pub winning_proposal: Option<Proposal>,
+    // true once every winner was tweeted
     pub proposal_tweeted: bool,
+    // the fields below are missing for rounds concluded before credits were recorded
+    #[serde(default)]
+    pub concluded_at: u64,
+    #[serde(default)]
+    pub reward_token: RewardToken,
+    #[serde(default)]
+    pub credits: Vec<Credit>,
+    #[serde(default)]
+    pub carried_over_e8s: u64,
+    // part of the round budget the treasury could not cover, it is dropped instead of
+    // carried over
+    #[serde(default)]
+    pub forfeited_e8s: u64,
+    // best first, the proposals keep the points they finished the round with
+    #[serde(default)]
+    pub winners: Vec<Winner>,
+}
+
+/// A concluded round with a page of its standings.
+#[derive(CandidType, Deserialize)]
+struct RoundDetails {
+    pub round: RoundResult,
+    // best first, starting at the requested rank
+    pub standings: Vec<ProposalStanding>,
+}
+
+/// How a proposal finished a round.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+struct ProposalStanding {
+    pub proposal_id: u32,
+    pub tweet: Tweet,
+    pub points: i32,
+    pub created_by: u32,
+    pub created_at: u64,
+    // distinct users, the points also depend on how much each vote counted for
+    pub nr_of_upvotes: u32,
+    pub nr_of_downvotes: u32,
+    pub nr_of_reports: u32,
+    pub won: bool,
+    pub rounds_survived: u32,
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+struct Winner {
+    pub proposal_id: u32,
+    pub proposal: Proposal,
+    pub tweeted: bool,
+}
+
+impl Storable for Proposal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for RoundResult {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ProposalStanding {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Payment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+fn init_proposals() -> StableBTreeMap<u32, Proposal, Memory> {
+    StableBTreeMap::init(memory::get_proposals_memory())
+}
+
+fn init_round_results() -> StableBTreeMap<u32, RoundResult, Memory> {
+    StableBTreeMap::init(memory::get_round_results_memory())
+}
+
+fn init_standings() -> StableBTreeMap<(u32, u32), ProposalStanding, Memory> {
+    StableBTreeMap::init(memory::get_standings_memory())
+}
+
+fn init_winner_trigrams() -> StableBTreeMap<(u32, u32), Trigrams, Memory> {
+    StableBTreeMap::init(memory::get_winner_trigrams_memory())
+}
+
+fn init_payment_history() -> StableLog<Payment, Memory, Memory> {
+    let (index_memory, data_memory) = memory::get_payment_history_memories();
+    StableLog::init(index_memory, data_memory).expect("failed to init payment history")
 }
 
 #[derive(CandidType, Serialize, Clone)]
@@ -169,6 +630,33 @@ pub enum Error {
     RoundResultDoesNotExist,
     UserProposalLimitReached,
     UsernameTooLong,
+    CannotRemoveLastAdmin,
+    StateSizeLimitReached,
+    StakingNotConfigured,
+    InsufficientStake,
+    NoDissolvedStake,
+    PaymentPending(u64),
+    PendingPaymentDoesNotExist,
+    InsufficientBalance { withdrawable_e8s: u64 },
+    BelowMinimumWithdrawal { min_withdrawal_e8s: u64 },
+    AmountDoesNotCoverFee { fee_e8s: u64 },
+    DonationAlreadyNotified,
+    BlockDoesNotExist,
+    NotADonation,
+    DonorAccountMismatch,
+    NotVerified,
+    VerificationStatusNotAllowed(VerificationStatus),
+    NotEnoughVoteCredits { remaining: u64 },
+    ProposalHidden,
+    CannotReportOwnProposal,
+    ReportLimitReached,
+    InvalidPattern(String),
+    ContainsBannedWord(String),
+    MatchesBannedPattern(String),
+    DomainDenied(String),
+    DomainNotAllowed(String),
+    DuplicateOfProposal(u32),
+    DuplicateOfRoundWinner(u32),
 }
 
 #[derive(CandidType, Serialize, Deserialize)]
@@ -177,12 +665,6 @@ enum Vote {
     Downvote,
 }
 
-struct WithdrawalInfo {
-    pub amount: u64,
-    pub deposit_address: AccountIdentifier,
-    pub id: u32,
-}
-
 impl State {
     fn delete_proposal(&mut self, proposal_id: u32) -> Result<(), Error> {
         let proposal = self
@@ -200,181 +682,987 @@ impl State {
             return Err(Error::NoPermission);
         }
         self.proposals.remove(&proposal_id);
+        self.proposal_index.remove(proposal_id);
         Ok(())
     }
 
+    /// Marks the first untweeted winner of a round as tweeted, the one `poll_untweeted`
+    /// returned for it.
     fn tweet_round_result(&mut self, index: usize) -> Result<(), Error> {
-        check_if_admin()?;
-        let round_result = self
+        let mut round_result = self
             .round_results
-            .get_mut(index)
+            .get(&(index as u32))
             .ok_or(Error::RoundResultDoesNotExist)?;
-        round_result.proposal_tweeted = true;
+        if let Some(winner) = round_result
+            .winners
+            .iter_mut()
+            .find(|winner| !winner.tweeted)
+        {
+            winner.tweeted = true;
+        }
+        round_result.proposal_tweeted = round_result.winners.iter().all(|winner| winner.tweeted);
+        if round_result.proposal_tweeted {
+            self.untweeted_rounds.remove(&(index as u32));
+        }
+        self.round_results.insert(index as u32, round_result);
         Ok(())
     }
 
     pub fn poll_untweeted(&self) -> Option<(usize, Tweet)> {
-        self.round_results
-            .iter()
-            .enumerate()
-            .find(|(_index, round_result)| !round_result.proposal_tweeted)
-            .map(|(index, round_result)| (index, round_result.winning_proposal.tweet.clone()))
+        self.untweeted_rounds.iter().find_map(|index| {
+            let round_result = self.round_results.get(index)?;
+            // rounds concluded before multiple winners only have `winning_proposal`
+            let tweet = round_result
+                .winners
+                .into_iter()
+                .find(|winner| !winner.tweeted)
+                .map(|winner| winner.proposal)
+                .or(round_result.winning_proposal)?
+                .tweet;
+            Some((*index as usize, tweet))
+        })
     }
 
-    fn report_proposal(&mut self, proposal_id: u32, caller: &Principal) -> Result<(), Error> {
-        self.users.get(caller).ok_or(Error::UserDoesNotExist)?;
-        let proposal = self
+    fn report_proposal(
+        &mut self,
+        proposal_id: u32,
+        reason: ReportReason,
+        caller: &Principal,
+    ) -> Result<(), Error> {
+        let reporter = self.users.get(caller).ok_or(Error::UserDoesNotExist)?;
+        let mut proposal = self
             .proposals
-            .get_mut(&proposal_id)
+            .get(&proposal_id)
             .ok_or(Error::ProposalDoesNotExist)?;
+        if proposal.created_by_id == reporter.id {
+            return Err(Error::CannotReportOwnProposal);
+        }
+        if proposal.reports.contains(caller) {
+            return Ok(());
+        }
+        if reporter.nr_reports_this_round >= self.settings.max_reports_per_round {
+            return Err(Error::ReportLimitReached);
+        }
+
+        let report_weight =
+            proposal.report_weight() + self.settings.report_weighting.weight(&reporter);
         proposal.reports.insert(*caller);
+        proposal.weighted_reports = Some(report_weight);
+        *proposal.report_reasons.entry(reason).or_default() += 1;
+        proposal.moderation_status = proposal
+            .moderation_status
+            .after_report(report_weight, self.settings.report_threshold);
+        self.users
+            .update(caller, |user| user.nr_reports_this_round += 1);
+        self.proposal_index.record_report(proposal_id, *caller);
+        self.proposal_index.set_hidden(
+            proposal_id,
+            proposal.moderation_status == ModerationStatus::Hidden,
+        );
+        self.proposals.insert(proposal_id, proposal);
         Ok(())
     }
 
+    /// Reported proposals that were not approved yet, hidden ones first, then by report
+    /// weight.
+    fn get_moderation_queue(&self, offset: u32, limit: u32) -> Vec<(u32, Proposal)> {
+        let mut queue = self
+            .proposals
+            .iter()
+            .filter(|(_, proposal)| {
+                !proposal.reports.is_empty()
+                    && proposal.moderation_status != ModerationStatus::Approved
+            })
+            .collect::<Vec<_>>();
+        queue.sort_by_key(|(id, proposal)| {
+            (
+                proposal.moderation_status != ModerationStatus::Hidden,
+                std::cmp::Reverse(proposal.report_weight()),
+                *id,
+            )
+        });
+        queue
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .collect()
+    }
+
+    /// Keeps a reported proposal, later reports no longer hide it.
+    fn approve_proposal(&mut self, proposal_id: u32) -> Result<(), Error> {
+        let mut proposal = self
+            .proposals
+            .get(&proposal_id)
+            .ok_or(Error::ProposalDoesNotExist)?;
+        proposal.moderation_status = ModerationStatus::Approved;
+        self.record_report_outcome(&mut proposal, false);
+        self.proposals.insert(proposal_id, proposal);
+        self.proposal_index.set_hidden(proposal_id, false);
+        Ok(())
+    }
+
+    /// Removes a proposal and takes `removal_karma_penalty` karma from its author.
+    fn remove_proposal(&mut self, proposal_id: u32) -> Result<(), Error> {
+        let mut proposal = self
+            .proposals
+            .remove(&proposal_id)
+            .ok_or(Error::ProposalDoesNotExist)?;
+        self.proposal_index.remove(proposal_id);
+        let penalty = self.settings.removal_karma_penalty;
+        self.users
+            .update_by_id(proposal.created_by_id, |user| user.karma -= penalty);
+        self.record_report_outcome(&mut proposal, true);
+        Ok(())
+    }
+
+    /// Counts a moderator decision towards the report history of everyone that reported
+    /// the proposal since the last decision, it changes how much their future reports weigh.
+    fn record_report_outcome(&mut self, proposal: &mut Proposal, upheld: bool) {
+        let unjudged = proposal
+            .reports
+            .difference(&proposal.judged_reports)
+            .copied()
+            .collect::<Vec<_>>();
+        for reporter in unjudged {
+            self.users.update(&reporter, |user| {
+                if upheld {
+                    user.reports_upheld += 1;
+                } else {
+                    user.reports_rejected += 1;
+                }
+            });
+            proposal.judged_reports.insert(reporter);
+        }
+    }
+
     fn update_account_identifier(
         &mut self,
         account_identifier: AccountIdentifier,
     ) -> Result<(), Error> {
         let caller = caller();
-        let user = self.users.get_mut(&caller).ok_or(Error::UserDoesNotExist)?;
-        user.deposit_address = Some(account_identifier);
-        Ok(())
+        self.users
+            .update(&caller, |user| {
+                user.deposit_address = Some(account_identifier);
+            })
+            .ok_or(Error::UserDoesNotExist)
+    }
+
+    fn update_icrc1_account(&mut self, account: Account) -> Result<(), Error> {
+        self.users
+            .update(&caller(), |user| {
+                user.icrc1_account = Some(account);
+            })
+            .ok_or(Error::UserDoesNotExist)
     }
 
     fn update_settings(&mut self, settings: Settings) {
+        // keep the start of the current round and let it end after the new duration
+        let round_start_time = self
+            .round_end_time
+            .saturating_sub(sec_to_nanos(self.settings.round_duration_seconds));
+        self.round_end_time = round_start_time + sec_to_nanos(settings.round_duration_seconds);
         self.settings = settings;
     }
 
-    fn handle_payment_result(
+    fn apply_upgrade_args(&mut self, args: UpgradeArgs) {
+        if let Some(settings) = args.settings {
+            self.update_settings(settings);
+        }
+        for admin in args.admins.unwrap_or_default() {
+            self.roles.grant(admin, Role::Admin);
+        }
+        if let Some(ledger_canister_id) = args.ledger_canister_id {
+            self.set_ledger_canister_id(ledger_canister_id);
+        }
+        if let Some(reward_token) = args.reward_token {
+            self.settings.reward_token = reward_token;
+        }
+        if let Some(staking_ledger_canister_id) = args.staking_ledger_canister_id {
+            self.staking_ledger_canister_id = Some(staking_ledger_canister_id);
+        }
+    }
+
+    fn set_ledger_canister_id(&mut self, ledger_canister_id: Principal) {
+        self.ledger_canister_id = ledger_canister_id;
+        // the known ICP balance belongs to the previous ledger
+        self.treasury.forget_balance(&RewardToken::Icp);
+    }
+
+    fn set_staking_ledger_canister_id(&mut self, staking_ledger_canister_id: Principal) {
+        self.staking_ledger_canister_id = Some(staking_ledger_canister_id);
+    }
+
+    fn get_staking_account(&self, caller: &Principal) -> Result<(Principal, Account), Error> {
+        let ledger_canister_id = self
+            .staking_ledger_canister_id
+            .ok_or(Error::StakingNotConfigured)?;
+        let user = self.users.get(caller).ok_or(Error::UserDoesNotExist)?;
+        Ok((ledger_canister_id, Stakes::staking_account(id(), user.id)))
+    }
+
+    fn get_staked_balance(&self, caller: &Principal) -> Result<Stake, Error> {
+        let user = self.users.get(caller).ok_or(Error::UserDoesNotExist)?;
+        Ok(self.stakes.get(user.id))
+    }
+
+    fn update_staked_tokens(
         &mut self,
-        withdrawal_info: WithdrawalInfo,
-        call_result: Result<Result<u64, TransferError>, (RejectionCode, String)>,
-    ) -> Result<BlockIndex, Error> {
-        let user = self
-            .users
-            .get_by_id(withdrawal_info.id)
-            .ok_or(Error::UserDoesNotExist)?;
-        match call_result {
-            Ok(Ok(block_index)) => {
-                self.payment_history.push(Payment {
-                    block_index,
-                    user_id: withdrawal_info.id,
-                    time: time(),
-                });
-                user.payment_in_progress = false;
-                return Ok(block_index);
+        caller: &Principal,
+        balance_e8s: u64,
+    ) -> Result<Stake, Error> {
+        let user = self.users.get(caller).ok_or(Error::UserDoesNotExist)?;
+        Ok(self.stakes.refresh(user.id, balance_e8s))
+    }
+
+    fn unstake(&mut self, caller: &Principal, amount_e8s: u64) -> Result<Stake, Error> {
+        let user = self.users.get(caller).ok_or(Error::UserDoesNotExist)?;
+        let dissolves_at = time() + sec_to_nanos(self.settings.dissolve_delay_seconds);
+        self.stakes.unstake(user.id, amount_e8s, dissolves_at)
+    }
+
+    fn stage_unstake_withdrawal(
+        &mut self,
+        caller: &Principal,
+        fee_e8s: u64,
+    ) -> Result<UnstakeWithdrawal, Error> {
+        let user = self.users.get(caller).ok_or(Error::UserDoesNotExist)?;
+        let to = user.icrc1_account.unwrap_or(Account {
+            owner: *caller,
+            subaccount: None,
+        });
+        self.stakes.stage_withdrawal(user.id, to, fee_e8s, time())
+    }
+
+    fn voting_power(&self, user_id: u32) -> i32 {
+        if self.settings.stake_e8s_per_extra_vote == 0 {
+            return 1;
+        }
+        let extra_votes = self.stakes.staked_e8s(user_id) / self.settings.stake_e8s_per_extra_vote;
+        1 + extra_votes.min(i32::MAX as u64 - 1) as i32
+    }
+
+    /// What a vote of `voter` counts for under the current voting mode. `votes` is only used
+    /// for quadratic voting.
+    fn vote_weight(&self, voter: &User, votes: Option<u32>) -> Result<VoteWeight, Error> {
+        check_verification_status(
+            &self.settings.verification_policy.can_vote,
+            voter.verification_status,
+        )?;
+        let voting_power = self.voting_power(voter.id);
+        let (weight, credit_budget) = match self.settings.voting_mode {
+            VotingMode::OnePersonOneVote => (voting_power, None),
+            VotingMode::KarmaWeighted {
+                karma_per_extra_vote,
+                max_extra_votes,
+            } => {
+                let extra_votes = (voter.karma.max(0) as u32)
+                    .checked_div(karma_per_extra_vote)
+                    .unwrap_or_default()
+                    .min(max_extra_votes)
+                    .min(i32::MAX as u32) as i32;
+                (voting_power.saturating_add(extra_votes), None)
             }
-            Ok(Err(e)) => {
-                user.withdrawable_e8s += withdrawal_info.amount;
-                user.payment_in_progress = false;
-                return Err(Error::PaymentError(e.to_string()));
+            VotingMode::VerifiedOnly => {
+                if !matches!(voter.verification_status, VerificationStatus::Verified) {
+                    return Err(Error::NotVerified);
+                }
+                (voting_power, None)
             }
-            Err((_, e)) => {
-                user.withdrawable_e8s += withdrawal_info.amount;
-                user.payment_in_progress = false;
-                return Err(Error::PaymentError(e));
+            VotingMode::Quadratic { credits_per_round } => {
+                let votes = votes.unwrap_or(1).clamp(1, i32::MAX as u32) as i32;
+                (votes, Some(credits_per_round))
             }
+        };
+        Ok(VoteWeight {
+            weight,
+            credit_budget,
+        })
+    }
+
+    fn end_round_early(&mut self) {
+        self.round_end_time = self.round_end_time.min(time());
+    }
+
+    fn extend_round(&mut self, seconds: u64) {
+        self.round_end_time += sec_to_nanos(seconds);
+    }
+
+    fn check_role(&self, caller: &Principal, role: Role) -> Result<(), Error> {
+        if !self.roles.has_role(caller, role) {
+            return Err(Error::NoPermission);
+        }
+        Ok(())
+    }
+
+    fn check_state_size(&self) -> Result<(), Error> {
+        if memory::stable_memory_size_bytes() >= self.settings.max_state_size_bytes as u64 {
+            return Err(Error::StateSizeLimitReached);
         }
+        Ok(())
     }
 
-    fn stage_payment(&mut self, caller: &Principal) -> Result<WithdrawalInfo, Error> {
-        let user = self.users.get_mut(caller).ok_or(Error::UserDoesNotExist)?;
+    fn grant_role(&mut self, principal: Principal, role: Role) {
+        self.roles.grant(principal, role);
+    }
 
-        if user.withdrawable_e8s == 0 {
-            return Err(Error::NoWithdrawableE8s);
+    fn revoke_role(&mut self, principal: Principal, role: Role) -> Result<(), Error> {
+        // never leave the canister without anyone that can manage roles
+        if role == Role::Admin
+            && self.roles.has_role(&principal, Role::Admin)
+            && self.roles.nr_of_admins() <= 1
+        {
+            return Err(Error::CannotRemoveLastAdmin);
         }
+        self.roles.revoke(&principal, role);
+        Ok(())
+    }
 
-        let deposit_address = user
-            .deposit_address
-            .as_ref()
-            .ok_or(Error::NoDepositAddress)?;
+    /// Settles a pending payment once the ledger outcome is known. Payments with an unknown
+    /// outcome stay pending and are retried by the reconciler.
+    fn handle_transfer_outcome(
+        &mut self,
+        payment_id: u64,
+        outcome: TransferOutcome,
+    ) -> Result<BlockIndex, Error> {
+        let error = match outcome {
+            TransferOutcome::Paid(block_index) => {
+                return self.settle_pending_payment(payment_id, Some(block_index))
+            }
+            TransferOutcome::Failed(e) => {
+                self.settle_pending_payment(payment_id, None)?;
+                return Err(Error::PaymentError(e));
+            }
+            TransferOutcome::Unknown(e) => e,
+        };
+        let payment = self
+            .pending_payments
+            .get_mut(&payment_id)
+            .ok_or(Error::PendingPaymentDoesNotExist)?;
+        payment.last_error = Some(error);
+        Err(Error::PaymentPending(payment_id))
+    }
 
-        if user.deposit_address.is_none() {
-            return Err(Error::NoDepositAddress);
+    /// Removes a pending payment. With a block index the payment is recorded as paid,
+    /// without one the amount is credited back to the user.
+    fn settle_pending_payment(
+        &mut self,
+        payment_id: u64,
+        block_index: Option<BlockIndex>,
+    ) -> Result<BlockIndex, Error> {
+        let payment = self
+            .pending_payments
+            .remove(&payment_id)
+            .ok_or(Error::PendingPaymentDoesNotExist)?;
+
+        let round_ids = self
+            .users
+            .update_by_id(payment.user_id, |user| {
+                user.payment_in_progress = false;
+                match block_index {
+                    Some(_) => user.pay_credits(&payment.token, payment.withdrawn_e8s),
+                    None => {
+                        *user.withdrawable_mut(&payment.token) += payment.withdrawn_e8s;
+                        Vec::new()
+                    }
+                }
+            })
+            .unwrap_or_default();
+        match block_index {
+            Some(_) => self
+                .treasury
+                .record_payout(&payment.token, payment.amount + payment.fee),
+            None => self.treasury.credit(&payment.token, payment.withdrawn_e8s),
         }
 
-        if user.payment_in_progress {
-            return Err(Error::PaymentInProgress);
-        } else {
-            user.payment_in_progress = true;
+        let block_index = block_index.ok_or_else(|| {
+            Error::PaymentError(
+                payment
+                    .last_error
+                    .unwrap_or_else(|| "payment was not executed".to_string()),
+            )
+        })?;
+        self.payment_history
+            .append(&Payment {
+                block_index,
+                user_id: payment.user_id,
+                time: time(),
+                token: payment.token,
+                round_ids,
+            })
+            .expect("failed to append payment to history");
+        Ok(block_index)
+    }
+
+    /// Returns the pending payments that are due for another attempt and marks them as attempted.
+    /// Payments older than the ledgers' deduplication window are left for an admin to resolve.
+    fn stage_payment_retries(&mut self, now: u64) -> Vec<PendingPayment> {
+        self.pending_payments
+            .values_mut()
+            .filter(|payment| {
+                now.saturating_sub(payment.last_attempt) >= payments::RETRY_AFTER_NANOS
+                    && now.saturating_sub(payment.created_at_time)
+                        < payments::MAX_RETRY_WINDOW_NANOS
+            })
+            .map(|payment| {
+                payment.last_attempt = now;
+                payment.attempts += 1;
+                payment.clone()
+            })
+            .collect()
+    }
+
+    /// Takes `amount_e8s`, or everything if none is given, from the caller's balance of
+    /// `token` and records it as a pending payment. Depending on the fee policy the ledger
+    /// fee is subtracted from the transferred amount or paid by the canister.
+    fn stage_payment(
+        &mut self,
+        caller: &Principal,
+        token: RewardToken,
+        amount_e8s: Option<u64>,
+        fee_e8s: u64,
+        now: u64,
+    ) -> Result<PendingPayment, Error> {
+        let icp_ledger_canister_id = self.ledger_canister_id;
+        let fee_policy = self.settings.fee_policy;
+        let min_withdrawal_e8s = self.settings.min_withdrawal_e8s;
+        let payment_id = self.next_payment_id;
+        let payment = self
+            .users
+            .update(caller, |user| {
+                let withdrawable_e8s = *user.withdrawable_mut(&token);
+                if withdrawable_e8s == 0 {
+                    return Err(Error::NoWithdrawableE8s);
+                }
+                let withdrawn_e8s = amount_e8s.unwrap_or(withdrawable_e8s);
+                if withdrawn_e8s > withdrawable_e8s {
+                    return Err(Error::InsufficientBalance { withdrawable_e8s });
+                }
+                if withdrawn_e8s == 0 || withdrawn_e8s < min_withdrawal_e8s {
+                    return Err(Error::BelowMinimumWithdrawal { min_withdrawal_e8s });
+                }
+                let amount = match fee_policy {
+                    FeePolicy::UserPays if withdrawn_e8s <= fee_e8s => {
+                        return Err(Error::AmountDoesNotCoverFee { fee_e8s });
+                    }
+                    FeePolicy::UserPays => withdrawn_e8s - fee_e8s,
+                    FeePolicy::TreasuryPays => withdrawn_e8s,
+                };
+
+                let target = match token {
+                    RewardToken::Icp => PaymentTarget::Icp {
+                        ledger_canister_id: icp_ledger_canister_id,
+                        to: user.deposit_address.ok_or(Error::NoDepositAddress)?,
+                    },
+                    RewardToken::Icrc1 { ledger_canister_id } => PaymentTarget::Icrc1 {
+                        ledger_canister_id,
+                        to: user.icrc1_account.ok_or(Error::NoDepositAddress)?,
+                    },
+                };
+
+                if user.payment_in_progress {
+                    return Err(Error::PaymentInProgress);
+                } else {
+                    user.payment_in_progress = true;
+                }
+
+                *user.withdrawable_mut(&token) -= withdrawn_e8s;
+
+                Ok(PendingPayment {
+                    id: payment_id,
+                    user_id: user.id,
+                    amount,
+                    fee: fee_e8s,
+                    withdrawn_e8s,
+                    token,
+                    target,
+                    created_at_time: now,
+                    last_attempt: now,
+                    attempts: 1,
+                    last_error: None,
+                })
+            })
+            .ok_or(Error::UserDoesNotExist)??;
+
+        // recorded before the transfer so a trap or an ambiguous reply never loses track of it
+        self.treasury.debit(&token, payment.withdrawn_e8s);
+        self.next_payment_id += 1;
+        self.pending_payments.insert(payment_id, payment.clone());
+        Ok(payment)
+    }
+
+    /// Records an ICP transfer from one of the caller's accounts into the canister's default
+    /// account as a donation and awards the Donated badge.
+    fn record_donation(
+        &mut self,
+        caller: &Principal,
+        block_index: BlockIndex,
+        block: Block,
+    ) -> Result<DonorTotal, Error> {
+        if self.donations.contains(block_index) {
+            return Err(Error::DonationAlreadyNotified);
+        }
+        let user = self.users.get(caller).ok_or(Error::UserDoesNotExist)?;
+
+        let canister_account = AccountIdentifier::new(&id(), &DEFAULT_SUBACCOUNT);
+        let (from, amount) = match block.transaction.operation {
+            Some(Operation::Transfer {
+                from, to, amount, ..
+            }) if to == canister_account => (from, amount),
+            _ => return Err(Error::NotADonation),
+        };
+        if from != AccountIdentifier::new(caller, &DEFAULT_SUBACCOUNT)
+            && user.deposit_address != Some(from)
+        {
+            return Err(Error::DonorAccountMismatch);
         }
 
-        let withdrawable_e8s = user.withdrawable_e8s;
-        user.withdrawable_e8s = 0;
+        let time = block.timestamp.timestamp_nanos;
+        let total = self.donations.record(Donation {
+            block_index,
+            from,
+            user_id: user.id,
+            amount_e8s: amount.e8s(),
+            time,
+        });
+        self.treasury
+            .record_deposit(&RewardToken::Icp, amount.e8s(), time);
+        self.users.update(caller, |user| {
+            if !user
+                .badges
+                .iter()
+                .any(|badge| matches!(badge, Badge::Donated))
+            {
+                user.badges.push(Badge::Donated);
+            }
+        });
+        Ok(total)
+    }
+
+    fn get_donor_leaderboard(&self, nr_of_donors: u32) -> Vec<Donor> {
+        self.donations
+            .get_top_donors(nr_of_donors)
+            .into_iter()
+            .map(|(user_id, total)| Donor {
+                user_id,
+                user_name: self
+                    .users
+                    .get_by_id(user_id)
+                    .and_then(|user| user.user_name),
+                total,
+            })
+            .collect()
+    }
 
-        Ok(WithdrawalInfo {
-            amount: withdrawable_e8s,
-            deposit_address: *deposit_address,
-            id: user.id,
-        })
+    fn pending_payments_e8s(&self, token: &RewardToken) -> u64 {
+        self.pending_payments
+            .values()
+            .filter(|payment| payment.token == *token)
+            .map(|payment| payment.amount + payment.fee)
+            .sum()
+    }
+
+    fn round_budget_e8s(&self) -> u64 {
+        self.settings.reward_per_round_e8s + self.reward_carry_over_e8s
+    }
+
+    /// Reward of the next round, lowered to what the treasury can cover.
+    fn next_round_reward_e8s(&self) -> u64 {
+        let token = self.settings.reward_token;
+        self.treasury.coverable_reward(
+            &token,
+            self.round_budget_e8s(),
+            self.pending_payments_e8s(&token),
+        )
+    }
+
+    fn get_treasury_status(&self) -> TreasuryStatus {
+        let token = self.settings.reward_token;
+        let next_round_reward_e8s = self.next_round_reward_e8s();
+        TreasuryStatus {
+            token,
+            balance: self.treasury.balance(&token),
+            withdrawable_e8s: self.treasury.withdrawable_e8s(&token),
+            pending_payments_e8s: self.pending_payments_e8s(&token),
+            reward_per_round_e8s: self.settings.reward_per_round_e8s,
+            reward_carry_over_e8s: self.reward_carry_over_e8s,
+            next_round_reward_e8s,
+            reward_status: treasury::reward_status(self.round_budget_e8s(), next_round_reward_e8s),
+        }
     }
 
     fn conclude_round(&mut self) {
         let user_ids_that_voted = self.users.get_all_users_that_voted();
 
-        let winning_proposal_id = self
-            .proposals
+        let ranked_proposal_ids = self.ranked_proposal_ids();
+        let winner_ids = ranked_proposal_ids
+            .iter()
+            .copied()
+            .filter(|id| {
+                let Some(proposal) = self.proposals.get(id) else {
+                    return false;
+                };
+                // hidden proposals wait for a moderator and can not win
+                proposal.moderation_status != ModerationStatus::Hidden
+                    && proposal.points >= self.settings.min_winning_points
+                    && proposal.nr_of_upvotes + proposal.nr_of_downvotes
+                        >= self.settings.min_distinct_voters
+            })
+            .take(self.settings.winners_per_round.max(1) as usize)
+            .collect::<Vec<_>>();
+        // upvoting a hidden proposal does not earn the top proposal tier either
+        let top_proposal_ids = ranked_proposal_ids
+            .iter()
+            .copied()
+            .filter(|id| {
+                self.proposals
+                    .get(id)
+                    .is_some_and(|proposal| proposal.moderation_status != ModerationStatus::Hidden)
+            })
+            .take(self.settings.reward_curve.top_proposals as usize)
+            .collect::<Vec<_>>();
+        let round_id = self.round_results.len() as u32;
+        let standings = self.proposal_standings(&ranked_proposal_ids, &winner_ids);
+        for (rank, standing) in standings.into_iter().enumerate() {
+            self.standings.insert((round_id, rank as u32), standing);
+        }
+
+        if winner_ids.is_empty() {
+            // nothing was proposed or reached quorum, the reward of this round goes to the next one
+            self.reward_carry_over_e8s += self.settings.reward_per_round_e8s;
+            self.round_results.insert(
+                round_id,
+                RoundResult {
+                    round_id,
+                    winning_proposal: None,
+                    proposal_tweeted: true,
+                    concluded_at: time(),
+                    reward_token: self.settings.reward_token,
+                    credits: Vec::new(),
+                    carried_over_e8s: self.reward_carry_over_e8s,
+                    forfeited_e8s: 0,
+                    winners: Vec::new(),
+                },
+            );
+            self.users.reset_round();
+            self.remove_and_reset_proposals();
+            return;
+        }
+
+        let winners = winner_ids
             .iter()
-            .max_by_key(|(_, proposal)| proposal.points)
-            .map(|(id, _)| *id);
-
-        if let Some(proposal_id) = winning_proposal_id {
-            let proposal = self.proposals.remove(&proposal_id).unwrap();
-            self.round_results.push(RoundResult {
-                round_id: self.round_results.len() as u32,
-                winning_proposal: proposal.clone(),
+            .map(|id| Winner {
+                proposal_id: *id,
+                proposal: self.proposals.remove(id).unwrap(),
+                tweeted: false,
+            })
+            .collect::<Vec<_>>();
+        for (rank, winner) in winners.iter().enumerate() {
+            self.winner_trigrams.insert(
+                (round_id, rank as u32),
+                Trigrams::of(winner.proposal.tweet.get_tweet()),
+            );
+        }
+
+        let forfeited_e8s = self
+            .round_budget_e8s()
+            .saturating_sub(self.next_round_reward_e8s());
+        // karma is still handed out when the treasury cannot cover any reward
+        let credits =
+            self.distribute_rewards(round_id, &winners, &top_proposal_ids, &user_ids_that_voted);
+        let concluded_at = time();
+        self.earnings
+            .record(round_id, concluded_at, self.settings.reward_token, &credits);
+        self.round_results.insert(
+            round_id,
+            RoundResult {
+                round_id,
+                winning_proposal: Some(winners[0].proposal.clone()),
                 proposal_tweeted: false,
-            });
+                concluded_at,
+                reward_token: self.settings.reward_token,
+                credits,
+                carried_over_e8s: self.reward_carry_over_e8s,
+                forfeited_e8s,
+                winners,
+            },
+        );
+        self.untweeted_rounds.insert(round_id);
 
-            let created_by = self.users.get_by_id(proposal.created_by_id);
-            if let Some(user) = created_by {
-                user.withdrawable_e8s +=
-                    (self.settings.reward_per_round_e8s as f64 * 0.3).round() as u64;
-                user.karma += 10;
+        self.users.reset_round();
+        //self.proposals.clear();
+        // clear all the proposals that have 1 or less points keep the rest but reset points
+
+        self.remove_and_reset_proposals()
+    }
+
+    fn proposal_standings(
+        &self,
+        ranked_proposal_ids: &[u32],
+        winner_ids: &[u32],
+    ) -> Vec<ProposalStanding> {
+        ranked_proposal_ids
+            .iter()
+            .filter_map(|id| {
+                let proposal = self.proposals.get(id)?;
+                Some(ProposalStanding {
+                    proposal_id: *id,
+                    tweet: proposal.tweet,
+                    points: proposal.points,
+                    created_by: proposal.created_by_id,
+                    created_at: proposal.created_at,
+                    nr_of_upvotes: proposal.nr_of_upvotes,
+                    nr_of_downvotes: proposal.nr_of_downvotes,
+                    nr_of_reports: proposal.reports.len() as u32,
+                    won: winner_ids.contains(id),
+                    rounds_survived: proposal.rounds_survived,
+                })
+            })
+            .collect()
+    }
+
+    /// A concluded round with the standings ranked `offset` and below.
+    fn get_round(&self, round_id: u32, offset: u32, limit: u32) -> Result<RoundDetails, Error> {
+        let round = self
+            .round_results
+            .get(&round_id)
+            .ok_or(Error::RoundResultDoesNotExist)?;
+        let standings = self
+            .standings
+            .range((round_id, offset)..=(round_id, u32::MAX))
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .map(|(_, standing)| standing)
+            .collect();
+        Ok(RoundDetails { round, standings })
+    }
+
+    /// Concluded rounds, newest first.
+    fn get_rounds(&self, offset: u32, limit: u32) -> Vec<RoundResult> {
+        self.round_results
+            .iter()
+            .rev()
+            .skip(offset as usize)
+            .take(limit.min(MAX_ROUNDS_PAGE_SIZE) as usize)
+            .map(|(_, round_result)| round_result)
+            .collect()
+    }
+
+    /// Ids of all proposals, best first. Ties go to the earliest proposal, then the lowest id.
+    fn ranked_proposal_ids(&self) -> Vec<u32> {
+        let mut proposals = self
+            .proposals
+            .iter()
+            .map(|(id, proposal)| (id, proposal.points, proposal.created_at))
+            .collect::<Vec<_>>();
+        proposals
+            .sort_by_key(|(id, points, created_at)| (std::cmp::Reverse(*points), *created_at, *id));
+        proposals.into_iter().map(|(id, _, _)| id).collect()
+    }
+
+    /// Credits the round reward to the authors of the winning proposals, who split the
+    /// author share evenly, and to the voters, weighted by the reward curve. Has to run before the votes of the round are reset.
+    fn distribute_rewards(
+        &mut self,
+        round_id: u32,
+        winners: &[Winner],
+        top_proposal_ids: &[u32],
+        user_ids_that_voted: &[u32],
+    ) -> Vec<Credit> {
+        let reward_token = self.settings.reward_token;
+        let curve = self.settings.reward_curve.clone();
+        let rewarded_statuses = self
+            .settings
+            .verification_policy
+            .can_receive_rewards
+            .clone();
+
+        let winning_proposal_ids = winners
+            .iter()
+            .map(|winner| winner.proposal_id)
+            .collect::<Vec<_>>();
+        // the upvote on a user's own proposal is cast automatically and earns nothing
+        let mut own_proposal_ids = HashMap::<u32, HashSet<u32>>::new();
+        let authors = self
+            .proposals
+            .iter()
+            .map(|(id, proposal)| (id, proposal.created_by_id))
+            .chain(
+                winners
+                    .iter()
+                    .map(|winner| (winner.proposal_id, winner.proposal.created_by_id)),
+            );
+        for (proposal_id, created_by_id) in authors {
+            own_proposal_ids
+                .entry(created_by_id)
+                .or_default()
+                .insert(proposal_id);
+        }
+        // voters that did not upvote anyone else's proposal get nothing
+        let voters = user_ids_that_voted
+            .iter()
+            .filter_map(|id| self.users.get_by_id(*id))
+            .filter_map(|user| {
+                let upvotes = match own_proposal_ids.get(&user.id) {
+                    Some(own) => user.upvotes.difference(own).copied().collect(),
+                    None => user.upvotes,
+                };
+                let tier = rewards::reward_tier(&upvotes, &winning_proposal_ids, top_proposal_ids)?;
+                let rewarded = rewarded_statuses.contains(&user.verification_status);
+                Some((user.id, rewarded.then_some(tier)))
+            })
+            .collect::<Vec<_>>();
+        let voter_weights = voters
+            .iter()
+            .filter_map(|(_, tier)| tier.map(|tier| curve.weight(tier)))
+            .collect::<Vec<_>>();
+        let split = rewards::split_reward(
+            self.next_round_reward_e8s(),
+            self.settings.author_reward_percent,
+            &voter_weights,
+        );
+        let per_author_e8s = split.author_e8s / winners.len() as u64;
+        // the shares of authors that may not receive rewards are carried over as well
+        let mut carry_over_e8s =
+            split.remainder_e8s + split.author_e8s - per_author_e8s * winners.len() as u64;
+
+        let mut credits = Vec::new();
+        for winner in winners {
+            let author_rewarded = self
+                .users
+                .get_by_id(winner.proposal.created_by_id)
+                .map(|user| rewarded_statuses.contains(&user.verification_status));
+            let author_e8s = if author_rewarded == Some(true) {
+                per_author_e8s
+            } else {
+                carry_over_e8s += per_author_e8s;
+                0
+            };
+            if author_rewarded.is_some() {
+                credits.push(Credit {
+                    user_id: winner.proposal.created_by_id,
+                    e8s: author_e8s,
+                    karma: 10,
+                    reason: CreditReason::WinningProposal,
+                });
             }
+        }
 
-            let total_users_users_that_voted = user_ids_that_voted.len() as f64;
-            for id in user_ids_that_voted {
-                let user = self.users.get_by_id(id);
-                if let Some(user) = user {
-                    user.withdrawable_e8s += (self.settings.reward_per_round_e8s as f64 * 0.7
-                        / total_users_users_that_voted)
-                        .round() as u64;
-                    user.karma += 5;
+        let mut voter_e8s = split.voter_e8s.into_iter();
+        for (user_id, tier) in voters {
+            let (e8s, reason) = match tier {
+                Some(tier) => (
+                    voter_e8s.next().unwrap_or_default(),
+                    CreditReason::Vote(tier),
+                ),
+                None => (0, CreditReason::Participation),
+            };
+            credits.push(Credit {
+                user_id,
+                e8s,
+                karma: 5,
+                reason,
+            });
+        }
+
+        for credit in &credits {
+            self.users.update_by_id(credit.user_id, |user| {
+                user.karma += credit.karma;
+                if credit.e8s > 0 {
+                    *user.withdrawable_mut(&reward_token) += credit.e8s;
+                    user.unpaid_credits.push(UnpaidCredit {
+                        round_id,
+                        token: reward_token,
+                        e8s: credit.e8s,
+                    });
                 }
-            }
+            });
+            self.treasury.credit(&reward_token, credit.e8s);
+        }
+        self.reward_carry_over_e8s = carry_over_e8s;
+        credits
+    }
 
-            self.users.reset_round();
-            //self.proposals.clear();
-            // clear all the proposals that have 1 or less points keep the rest but reset points
+    /// Credits of a user across all rounds, newest round first.
+    fn get_user_earnings(&self, user_id: u32, offset: u32, limit: u32) -> Vec<Earning> {
+        self.earnings
+            .get(user_id, offset as usize, limit.min(MAX_PAGE_SIZE) as usize)
+    }
+
+    fn remove_and_reset_proposals(&mut self) {
+        let carry_over_policy = self.settings.carry_over_policy;
+        let proposals = self.proposals.iter().collect::<Vec<_>>();
+        for (id, mut proposal) in proposals {
+            match carry_over_policy.carried_points(proposal.points, proposal.rounds_survived) {
+                None => {
+                    self.proposals.remove(&id);
+                }
+                Some(points) => {
+                    proposal.points = points;
+                    proposal.rounds_survived += 1;
+                    // the author keeps their upvote, it is part of the carried points
+                    proposal.nr_of_upvotes = 1;
+                    proposal.nr_of_downvotes = 0;
+                    self.users.update_by_id(proposal.created_by_id, |user| {
+                        user.upvotes.insert(id);
+                    });
+                    self.proposals.insert(id, proposal);
+                }
+            }
+        }
+        self.rebuild_proposal_index();
+    }
 
-            self.remove_and_reset_proposals()
+    /// The checks a proposed tweet has to pass, in order.
+    fn content_checks(&self) -> Vec<Box<dyn ContentCheck + '_>> {
+        let policy = &self.content_policy;
+        let mut checks: Vec<Box<dyn ContentCheck>> = vec![
+            Box::new(BannedWords::new(&policy.banned_words)),
+            Box::new(&self.banned_patterns),
+            Box::new(LinkPolicy::new(
+                &policy.allowed_domains,
+                &policy.denied_domains,
+            )),
+        ];
+        if policy.max_similarity_percent > 0 {
+            let proposals = self
+                .proposal_index
+                .trigrams()
+                .map(|(id, trigrams)| (Earlier::Proposal(id), Cow::Borrowed(trigrams)));
+            let round_winners = self
+                .winner_trigrams
+                .iter()
+                .map(|((round_id, _), trigrams)| {
+                    (Earlier::RoundWinner(round_id), Cow::Owned(trigrams))
+                });
+            checks.push(Box::new(NearDuplicates::new(
+                proposals.chain(round_winners).collect(),
+                policy.max_similarity_percent,
+            )));
         }
+        checks
     }
 
-    fn remove_and_reset_proposals(&mut self) {
-        self.proposals.retain(|_, proposal| {
-            if proposal.points < 1 {
-                false
-            } else {
-                proposal.points = 1;
-                true
-            }
-        });
-        self.proposals.iter().for_each(|(id, proposal)| {
-            let user = self.users.get_by_id(proposal.created_by_id);
-            if let Some(user) = user {
-                user.upvotes.insert(*id);
-            }
-        });
+    fn update_content_policy(&mut self, content_policy: ContentPolicy) -> Result<(), Error> {
+        self.banned_patterns = BannedPatterns::compile(&content_policy.banned_patterns)?;
+        self.content_policy = content_policy;
+        Ok(())
+    }
+
+    fn rebuild_proposal_index(&mut self) {
+        self.proposal_index = ProposalIndex::build(self.proposals.iter());
     }
 
     fn create_proposal(&mut self, create_args: CreateProposalArg) -> Result<u32, Error> {
-        if self.proposals.len() as u32 >= self.settings.max_proposals_per_round {
+        if self.proposals.len() >= self.settings.max_proposals_per_round as u64 {
             return Err(Error::ProposalLimitReached);
         }
+        self.check_state_size()?;
         let user = self.users.get(&caller()).ok_or(Error::UserDoesNotExist)?;
 
+        check_verification_status(
+            &self.settings.verification_policy.can_propose,
+            user.verification_status,
+        )?;
+
         if user.nr_posts_this_round >= self.settings.max_proposals_per_user {
             return Err(Error::UserProposalLimitReached);
         }
@@ -389,28 +1677,49 @@ impl State {
         {
             return Err(Error::TweetTooLong);
         }
+        content_policy::check_content(&self.content_checks(), create_args.tweet.get_tweet())?;
+
+        // the author's own upvote must be possible before anything is stored, an error
+        // after the proposal is inserted would leave it in the pool
+        let vote_weight = self.vote_weight(&user, None)?;
+        users::check_vote_credits(&user, &vote_weight)?;
 
         let proposal_id = self.next_proposal_id;
-        self.proposals.insert(
-            proposal_id,
-            Proposal {
-                points: 0,
-                created_by_id: user.id,
-                created_at: time(),
-                tweet: create_args.tweet,
-                reports: HashSet::new(),
-            },
-        );
+        let proposal = Proposal {
+            points: 0,
+            created_by_id: user.id,
+            created_at: time(),
+            tweet: create_args.tweet,
+            reports: HashSet::new(),
+            rounds_survived: 0,
+            report_reasons: HashMap::new(),
+            moderation_status: ModerationStatus::Visible,
+            weighted_reports: None,
+            judged_reports: HashSet::new(),
+            nr_of_upvotes: 0,
+            nr_of_downvotes: 0,
+        };
+        self.proposal_index.insert(proposal_id, &proposal);
+        self.proposals.insert(proposal_id, proposal);
         self.next_proposal_id += 1;
-        self.vote_for_proposal(proposal_id, Vote::Upvote)?;
+        self.vote_for_proposal(proposal_id, Vote::Upvote, None)?;
         Ok(proposal_id)
     }
 
     fn verify_user(&mut self, id: u32) -> Result<(), Error> {
-        let user = self.users.get_by_id(id).ok_or(Error::UserDoesNotExist)?;
+        self.set_verification_status(id, VerificationStatus::Verified)
+    }
 
-        user.verification_status = VerificationStatus::Verified;
-        Ok(())
+    fn set_verification_status(
+        &mut self,
+        id: u32,
+        verification_status: VerificationStatus,
+    ) -> Result<(), Error> {
+        self.users
+            .update_by_id(id, |user| {
+                user.verification_status = verification_status;
+            })
+            .ok_or(Error::UserDoesNotExist)
     }
 
     fn create_user(&mut self, create_args: CreateUserArg) -> Result<u32, Error> {
@@ -418,6 +1727,7 @@ impl State {
         if self.users.principal_in_use(&create_args.principal) {
             return Err(Error::UserExistsAlready);
         }
+        self.check_state_size()?;
 
         let badges = if self.next_user_id < 1000 {
             vec![Badge::OG]
@@ -434,15 +1744,22 @@ impl State {
                 new_auth: None,
                 karma: 10,
                 withdrawable_e8s: 0,
+                withdrawable_tokens: HashMap::new(),
                 deposit_address: None,
+                icrc1_account: None,
                 payment_in_progress: false,
-                created_proposal: None,
                 upvotes: HashSet::new(),
                 downvotes: HashSet::new(),
+                vote_weights: HashMap::new(),
+                vote_credits_used: 0,
+                unpaid_credits: Vec::new(),
                 verification_status: VerificationStatus::Unverified,
                 last_updated: time(),
                 badges,
                 nr_posts_this_round: 0,
+                nr_reports_this_round: 0,
+                reports_upheld: 0,
+                reports_rejected: 0,
             },
         );
         self.next_user_id += 1;
@@ -454,24 +1771,23 @@ impl State {
         new_principal: Principal,
         login_method: LoginMethod,
     ) -> Result<(), Error> {
-        if let Some(user) = self.users.get_mut(&caller()) {
-            user.new_auth = Some((new_principal, login_method));
-            Ok(())
-        } else {
-            Err(Error::UserDoesNotExist)
-        }
+        self.users
+            .update(&caller(), |user| {
+                user.new_auth = Some((new_principal, login_method));
+            })
+            .ok_or(Error::UserDoesNotExist)
     }
 
     fn update_username(&mut self, new_username: String) -> Result<(), Error> {
-        let user = self
-            .users
-            .get_mut(&caller())
-            .ok_or(Error::UserDoesNotExist)?;
-        if new_username.len() > 15 {
-            return Err(Error::UsernameTooLong);
-        }
-        user.user_name = Some(new_username);
-        Ok(())
+        self.users
+            .update(&caller(), |user| {
+                if new_username.len() > 15 {
+                    return Err(Error::UsernameTooLong);
+                }
+                user.user_name = Some(new_username);
+                Ok(())
+            })
+            .ok_or(Error::UserDoesNotExist)?
     }
 
     fn update_user_principal(&mut self, old_principal: Principal) -> Result<(), Error> {
@@ -500,142 +1816,261 @@ impl State {
         Ok(())
     }
 
-    fn vote_for_proposal(&mut self, proposal_id: u32, vote: Vote) -> Result<(), Error> {
+    fn vote_for_proposal(
+        &mut self,
+        proposal_id: u32,
+        vote: Vote,
+        votes: Option<u32>,
+    ) -> Result<(), Error> {
         ic_cdk::println!(
             "Voting for proposal {} with caller {}",
             proposal_id,
             &caller().to_text()
         );
-        let proposal = self
+        let mut proposal = self
             .proposals
-            .get_mut(&proposal_id)
+            .get(&proposal_id)
             .ok_or(Error::ProposalDoesNotExist)?;
 
+        if proposal.moderation_status == ModerationStatus::Hidden {
+            return Err(Error::ProposalHidden);
+        }
+
         let created_by = proposal.created_by_id;
         let caller_id = caller();
+        let voter = self.users.get(&caller_id).ok_or(Error::UserDoesNotExist)?;
+        let vote_weight = self.vote_weight(&voter, votes)?;
+        let upvoted = voter.upvotes.contains(&proposal_id);
+        let downvoted = voter.downvotes.contains(&proposal_id);
+        // a vote in the same direction cancels the earlier one
+        let voted_after = match vote {
+            Vote::Upvote => (!upvoted, false),
+            Vote::Downvote => (false, !downvoted),
+        };
 
         match vote {
             Vote::Upvote => {
-                let upvote_delta = self.users.get_upvote_delta(&caller_id, &proposal_id)?;
+                let upvote_delta =
+                    self.users
+                        .get_upvote_delta(&caller_id, &proposal_id, &vote_weight)?;
                 proposal.points += upvote_delta;
                 self.users
-                    .get_by_id(created_by)
-                    .ok_or(Error::UserDoesNotExist)?
-                    .karma += upvote_delta;
+                    .update_by_id(created_by, |user| user.karma += upvote_delta)
+                    .ok_or(Error::UserDoesNotExist)?;
             }
             Vote::Downvote => {
-                let downvotes = self.users.get_downvote_delta(&caller_id, &proposal_id)?;
+                let downvotes =
+                    self.users
+                        .get_downvote_delta(&caller_id, &proposal_id, &vote_weight)?;
                 proposal.points += downvotes;
                 self.users
-                    .get_by_id(created_by)
-                    .ok_or(Error::UserDoesNotExist)?
-                    .karma -= downvotes;
+                    .update_by_id(created_by, |user| user.karma -= downvotes)
+                    .ok_or(Error::UserDoesNotExist)?;
             }
         }
+        proposal.nr_of_upvotes =
+            proposal.nr_of_upvotes + u32::from(voted_after.0) - u32::from(upvoted);
+        proposal.nr_of_downvotes =
+            proposal.nr_of_downvotes + u32::from(voted_after.1) - u32::from(downvoted);
+        self.proposal_index.record_vote(proposal_id, &proposal);
+        self.proposals.insert(proposal_id, proposal);
         Ok(())
     }
 
     fn get_proposals_info(&self, caller: &Principal) -> Vec<ProposalInfo> {
         let user = self.users.get(caller);
-        match user {
-            Some(user) => self
-                .proposals
-                .iter()
-                .map(|(id, proposal)| {
-                    let upvote_status = if user.upvotes.contains(id) {
-                        UpvoteStatus::Upvoted
-                    } else if user.downvotes.contains(id) {
-                        UpvoteStatus::Downvoted
-                    } else {
-                        UpvoteStatus::NotVoted
-                    };
-                    ProposalInfo::from_proposal(proposal, *id, upvote_status, *caller)
-                })
-                .collect(),
-            None => {
-                return self
-                    .proposals
-                    .iter()
-                    .map(|(id, proposal)| {
-                        ProposalInfo::from_proposal(proposal, *id, UpvoteStatus::NotVoted, *caller)
-                    })
-                    .collect()
-            }
-        }
+        self.proposals
+            .iter()
+            .filter(|(_, proposal)| proposal.moderation_status != ModerationStatus::Hidden)
+            .map(|(id, proposal)| proposal_info(id, &proposal, user.as_ref(), caller))
+            .collect()
+    }
+
+    fn get_proposals_page(
+        &self,
+        caller: &Principal,
+        sort: ProposalSort,
+        offset: u32,
+        limit: u32,
+        filter: &ProposalFilter,
+    ) -> Vec<ProposalInfo> {
+        let user = self.users.get(caller);
+        let voted_by_caller = user
+            .as_ref()
+            .map(|user| user.upvotes.union(&user.downvotes).copied().collect())
+            .unwrap_or_default();
+        self.proposal_index
+            .page(
+                sort,
+                filter,
+                caller,
+                &voted_by_caller,
+                offset as usize,
+                limit.min(MAX_PAGE_SIZE) as usize,
+            )
+            .into_iter()
+            .filter_map(|id| {
+                let proposal = self.proposals.get(&id)?;
+                Some(proposal_info(id, &proposal, user.as_ref(), caller))
+            })
+            .collect()
     }
 }
 
+/// The proposal as `caller` sees it, `user` is the caller's account if they have one.
+fn proposal_info(
+    id: u32,
+    proposal: &Proposal,
+    user: Option<&User>,
+    caller: &Principal,
+) -> ProposalInfo {
+    let Some(user) = user else {
+        return ProposalInfo::from_proposal(proposal, id, UpvoteStatus::NotVoted, 0, *caller);
+    };
+    let upvote_status = if user.upvotes.contains(&id) {
+        UpvoteStatus::Upvoted
+    } else if user.downvotes.contains(&id) {
+        UpvoteStatus::Downvoted
+    } else {
+        UpvoteStatus::NotVoted
+    };
+    let vote_weight = match upvote_status {
+        UpvoteStatus::NotVoted => 0,
+        _ => user.vote_weights.get(&id).copied().unwrap_or(1),
+    };
+    ProposalInfo::from_proposal(proposal, id, upvote_status, vote_weight, *caller)
+}
+
+// most items a paginated query returns at once
+const MAX_PAGE_SIZE: u32 = 100;
+// a round holds the credits of all its voters, so fewer of them fit in a response
+const MAX_ROUNDS_PAGE_SIZE: u32 = 10;
+
 fn sec_to_nanos(secs: u64) -> u64 {
     secs * 1000000000
 }
 
-fn set_timer_recursive(duration: Duration) {
-    ic_cdk_timers::set_timer(duration, move || {
+/// (Re)arms the round timer so that it fires at the persisted `round_end_time`.
+/// Timers do not survive upgrades, so this has to be called again after every upgrade
+/// and whenever the round end time changes.
+fn schedule_round_end() {
+    let delay = Access::with_state(|state| state.round_end_time.saturating_sub(time()));
+    let timer_id = ic_cdk_timers::set_timer(Duration::from_nanos(delay), || {
         ic_cdk::println!("concluding round");
         Access::with_state_mut(|state| state.conclude_round());
         Access::with_state_mut(|state| {
             state.round_end_time = time() + sec_to_nanos(state.settings.round_duration_seconds);
         });
-        set_timer_recursive(duration);
+        schedule_round_end();
+    });
+    ROUND_TIMER.with(|timer| {
+        if let Some(previous_timer_id) = timer.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(previous_timer_id);
+        }
     });
 }
 
 #[init]
 #[candid_method(init)]
-fn init() {
-    let round_duration_seconds = 60; //3600 * 12;
+fn init(args: JohnDaoArgs) {
+    let JohnDaoArgs::Init(args) = args else {
+        ic_cdk::trap("expected init arguments");
+    };
+    let mut settings = args.settings.unwrap_or_default();
+    if let Some(reward_token) = args.reward_token {
+        settings.reward_token = reward_token;
+    }
     STATE.with(|state| {
         *state.borrow_mut() = Some(State {
+            version: CURRENT_VERSION,
             next_user_id: 1,
             users: Users::new(),
             next_proposal_id: 0,
-            proposals: HashMap::new(),
-            round_results: Vec::new(),
-            payment_history: Vec::new(),
-            settings: Settings {
-                round_duration_seconds: round_duration_seconds,
-                max_state_size_bytes: 1_000_000_000,
-                reward_per_round_e8s: 100_000_000, //1 icp 100_000_000
-                max_proposals_per_round: 1500,
-                max_proposals_per_user: 5,
-                max_tweet_length: 280,
-            },
-            round_end_time: time() + sec_to_nanos(round_duration_seconds),
+            proposals: init_proposals(),
+            round_results: init_round_results(),
+            standings: init_standings(),
+            untweeted_rounds: BTreeSet::new(),
+            payment_history: init_payment_history(),
+            round_end_time: time() + sec_to_nanos(settings.round_duration_seconds),
+            settings,
             last_user_backup: 0,
-            test: Some("test".to_string()),
+            roles: Roles::with_admins(args.admins),
+            ledger_canister_id: args
+                .ledger_canister_id
+                .unwrap_or(MAINNET_LEDGER_CANISTER_ID),
+            stakes: Stakes::new(),
+            staking_ledger_canister_id: args.staking_ledger_canister_id,
+            next_payment_id: 0,
+            pending_payments: BTreeMap::new(),
+            treasury: Treasury::default(),
+            donations: Donations::new(),
+            earnings: Earnings::new(),
+            reward_carry_over_e8s: 0,
+            proposal_index: ProposalIndex::default(),
+            content_policy: ContentPolicy::default(),
+            banned_patterns: BannedPatterns::default(),
+            winner_trigrams: init_winner_trigrams(),
         });
     });
 
-    set_timer_recursive(Duration::from_secs(round_duration_seconds));
+    schedule_round_end();
+    schedule_payment_reconciliation();
+    schedule_treasury_refresh();
 }
 
-fn check_if_admin() -> Result<(), Error> {
-    let caller = caller();
-    // test principal = rwbxt-jvr66-qvpbz-2kbh3-u226q-w6djk-b45cp-66ewo-tpvng-thbkh-wae
-
-    if caller
-        != Principal::from_text("25xau-y2lix-xnu4a-gfgnv-nsm4l-5vsde-3hsvd-ol3o6-qwihp-5crn7-sqe")
-            .unwrap()
-    {
-        return Err(Error::NoPermission);
+fn check_verification_status(
+    allowed: &[VerificationStatus],
+    verification_status: VerificationStatus,
+) -> Result<(), Error> {
+    if !allowed.contains(&verification_status) {
+        return Err(Error::VerificationStatusNotAllowed(verification_status));
     }
     Ok(())
 }
 
+fn check_role(role: Role) -> Result<(), Error> {
+    Access::with_state(|state| state.check_role(&caller(), role))
+}
+
+#[update]
+#[candid_method(update)]
+fn grant_role(principal: Principal, role: Role) -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| state.grant_role(principal, role));
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn revoke_role(principal: Principal, role: Role) -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| state.revoke_role(principal, role))
+}
+
 #[update]
 #[candid_method(update)]
 fn create_user(create_args: CreateUserArg) -> Result<u32, Error> {
-    check_if_admin()?;
+    check_role(Role::UserCreator)?;
     Access::with_state_mut(|state| state.create_user(create_args))
 }
 
 #[update]
 #[candid_method(update)]
 fn verify_user(user_id: u32) -> Result<(), Error> {
-    check_if_admin()?;
+    check_role(Role::Moderator)?;
     Access::with_state_mut(|state| state.verify_user(user_id))
 }
 
+#[update]
+#[candid_method(update)]
+fn set_verification_status(
+    user_id: u32,
+    verification_status: VerificationStatus,
+) -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| state.set_verification_status(user_id, verification_status))
+}
+
 #[update]
 #[candid_method(update)]
 fn stage_new_principal(principal: Principal, login_method: LoginMethod) -> Result<(), Error> {
@@ -650,8 +2085,8 @@ fn update_user_principal(new_principal: Principal) -> Result<(), Error> {
 
 #[update]
 #[candid_method(update)]
-fn vote_for_proposal(proposal_id: u32, vote: Vote) -> Result<(), Error> {
-    Access::with_state_mut(|state| state.vote_for_proposal(proposal_id, vote))
+fn vote_for_proposal(proposal_id: u32, vote: Vote, votes: Option<u32>) -> Result<(), Error> {
+    Access::with_state_mut(|state| state.vote_for_proposal(proposal_id, vote, votes))
 }
 
 #[update]
@@ -663,8 +2098,49 @@ fn create_proposal(create_args: CreateProposalArg) -> Result<u32, Error> {
 #[update]
 #[candid_method(update)]
 fn update_settings(settings: Settings) -> Result<(), Error> {
-    check_if_admin()?;
+    check_role(Role::Admin)?;
     Access::with_state_mut(|state| state.update_settings(settings));
+    schedule_round_end();
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn update_content_policy(content_policy: ContentPolicy) -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| state.update_content_policy(content_policy))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_content_policy() -> Result<ContentPolicy, Error> {
+    check_role(Role::Admin)?;
+    Access::with_state(|state| Ok(state.content_policy.clone()))
+}
+
+#[update]
+#[candid_method(update)]
+fn set_ledger_canister_id(ledger_canister_id: Principal) -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| state.set_ledger_canister_id(ledger_canister_id));
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn end_round_early() -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| state.end_round_early());
+    schedule_round_end();
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn extend_round(seconds: u64) -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| state.extend_round(seconds));
+    schedule_round_end();
     Ok(())
 }
 
@@ -674,10 +2150,16 @@ fn update_account_identifier(account_identifier: AccountIdentifier) -> Result<()
     Access::with_state_mut(|state| state.update_account_identifier(account_identifier))
 }
 
+#[update]
+#[candid_method(update)]
+fn update_icrc1_account(account: Account) -> Result<(), Error> {
+    Access::with_state_mut(|state| state.update_icrc1_account(account))
+}
+
 #[update]
 #[candid_method(update)]
 fn update_last_backup_time(time: u64) -> Result<(), Error> {
-    check_if_admin()?;
+    check_role(Role::UserCreator)?;
     Access::with_state_mut(|state| state.last_user_backup = time);
     Ok(())
 }
@@ -685,13 +2167,37 @@ fn update_last_backup_time(time: u64) -> Result<(), Error> {
 #[update]
 #[candid_method(update)]
 fn post_tweet(index: usize) -> Result<(), Error> {
+    check_role(Role::TweetPoster)?;
     Access::with_state_mut(|state| state.tweet_round_result(index))
 }
 
 #[update]
 #[candid_method(update)]
-fn report_proposal(proposal_id: u32) -> Result<(), Error> {
-    Access::with_state_mut(|state| state.report_proposal(proposal_id, &caller()))
+fn report_proposal(proposal_id: u32, reason: Option<ReportReason>) -> Result<(), Error> {
+    Access::with_state_mut(|state| {
+        state.report_proposal(proposal_id, reason.unwrap_or_default(), &caller())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_moderation_queue(offset: u32, limit: u32) -> Result<Vec<(u32, Proposal)>, Error> {
+    check_role(Role::Moderator)?;
+    Access::with_state(|state| Ok(state.get_moderation_queue(offset, limit)))
+}
+
+#[update]
+#[candid_method(update)]
+fn approve_proposal(proposal_id: u32) -> Result<(), Error> {
+    check_role(Role::Moderator)?;
+    Access::with_state_mut(|state| state.approve_proposal(proposal_id))
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_proposal(proposal_id: u32) -> Result<(), Error> {
+    check_role(Role::Moderator)?;
+    Access::with_state_mut(|state| state.remove_proposal(proposal_id))
 }
 
 #[update]
@@ -708,31 +2214,241 @@ fn delete_proposal(proposal_id: u32) -> Result<(), Error> {
 
 #[update]
 #[candid_method(update)]
-async fn claim_reward() -> Result<BlockIndex, Error> {
+async fn claim_reward(
+    token: Option<RewardToken>,
+    amount_e8s: Option<u64>,
+) -> Result<BlockIndex, Error> {
+    let caller = caller();
+    let token = token.unwrap_or_default();
+    let fee_e8s = payments::fee(&token).await?;
+    let payment = Access::with_state_mut(|state| {
+        state.stage_payment(&caller, token, amount_e8s, fee_e8s, time())
+    })?;
+
+    let outcome = payments::transfer(&payment).await;
+    Access::with_state_mut(|state| state.handle_transfer_outcome(payment.id, outcome))
+}
+
+/// Retries every pending payment that is due, the ledger deduplicates the ones that
+/// already went through.
+async fn reconcile_payments_inner() {
+    let payments = Access::with_state_mut(|state| state.stage_payment_retries(time()));
+    for payment in payments {
+        let outcome = payments::transfer(&payment).await;
+        if let Err(Error::PaymentPending(id)) =
+            Access::with_state_mut(|state| state.handle_transfer_outcome(payment.id, outcome))
+        {
+            ic_cdk::println!("payment {} is still pending", id);
+        }
+    }
+}
+
+fn schedule_payment_reconciliation() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_nanos(payments::RETRY_AFTER_NANOS * 2),
+        || ic_cdk::spawn(reconcile_payments_inner()),
+    );
+}
+
+/// Fetches the balance of the canister's default account on the ledger of the reward token.
+async fn refresh_treasury_balance_inner() -> Result<TreasuryStatus, Error> {
+    let (token, icp_ledger_canister_id) =
+        Access::with_state(|state| (state.settings.reward_token, state.ledger_canister_id));
+    let balance_e8s = match token {
+        RewardToken::Icp => ic_ledger_types::account_balance(
+            icp_ledger_canister_id,
+            AccountBalanceArgs {
+                account: AccountIdentifier::new(&id(), &DEFAULT_SUBACCOUNT),
+            },
+        )
+        .await
+        .map(|balance| balance.e8s()),
+        RewardToken::Icrc1 { ledger_canister_id } => icrc1::balance_of(
+            ledger_canister_id,
+            Account {
+                owner: id(),
+                subaccount: None,
+            },
+        )
+        .await
+        .map(|balance| icrc1::nat_to_u64(&balance)),
+    }
+    .map_err(|(_, e)| Error::PaymentError(e))?;
+
+    Access::with_state_mut(|state| {
+        state.treasury.set_balance(token, balance_e8s, time());
+        Ok(state.get_treasury_status())
+    })
+}
+
+fn schedule_treasury_refresh() {
+    let refresh = || {
+        ic_cdk::spawn(async {
+            if let Err(Error::PaymentError(e)) = refresh_treasury_balance_inner().await {
+                ic_cdk::println!("failed to refresh treasury balance: {}", e);
+            }
+        })
+    };
+    ic_cdk_timers::set_timer(Duration::ZERO, refresh);
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(treasury::BALANCE_REFRESH_INTERVAL_SECONDS),
+        refresh,
+    );
+}
+
+#[update]
+#[candid_method(update)]
+async fn refresh_treasury_balance() -> Result<TreasuryStatus, Error> {
+    check_role(Role::Admin)?;
+    refresh_treasury_balance_inner().await
+}
+
+#[update]
+#[candid_method(update)]
+async fn notify_donation(block_index: BlockIndex) -> Result<DonorTotal, Error> {
+    let caller = caller();
+    let ledger_canister_id = Access::with_state(|state| {
+        if state.donations.contains(block_index) {
+            return Err(Error::DonationAlreadyNotified);
+        }
+        Ok(state.ledger_canister_id)
+    })?;
+
+    let block = donations::query_block(ledger_canister_id, block_index)
+        .await?
+        .ok_or(Error::BlockDoesNotExist)?;
+
+    Access::with_state_mut(|state| state.record_donation(&caller, block_index, block))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_donor_leaderboard(nr_of_donors: u32) -> Vec<Donor> {
+    Access::with_state(|state| state.get_donor_leaderboard(nr_of_donors))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_treasury_status() -> TreasuryStatus {
+    Access::with_state(|state| state.get_treasury_status())
+}
+
+#[update]
+#[candid_method(update)]
+async fn reconcile_payments() -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    reconcile_payments_inner().await;
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn resolve_pending_payment(
+    payment_id: u64,
+    block_index: Option<BlockIndex>,
+) -> Result<Option<BlockIndex>, Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| {
+        match state.settle_pending_payment(payment_id, block_index) {
+            Ok(block_index) => Ok(Some(block_index)),
+            // the refund went through, the error only reports that nothing was paid
+            Err(Error::PaymentError(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_pending_payments() -> Result<Vec<PendingPayment>, Error> {
+    check_role(Role::Admin)?;
+    Ok(Access::with_state(|state| {
+        state.pending_payments.values().cloned().collect()
+    }))
+}
+
+#[update]
+#[candid_method(update)]
+fn set_staking_ledger_canister_id(staking_ledger_canister_id: Principal) -> Result<(), Error> {
+    check_role(Role::Admin)?;
+    Access::with_state_mut(|state| {
+        state.set_staking_ledger_canister_id(staking_ledger_canister_id)
+    });
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+async fn update_staked_tokens() -> Result<Stake, Error> {
+    let caller = caller();
+    let (ledger_canister_id, staking_account) =
+        Access::with_state(|state| state.get_staking_account(&caller))?;
+
+    let balance = icrc1::balance_of(ledger_canister_id, staking_account)
+        .await
+        .map_err(|(_, e)| Error::PaymentError(e))?;
+
+    Access::with_state_mut(|state| state.update_staked_tokens(&caller, icrc1::nat_to_u64(&balance)))
+}
+
+#[update]
+#[candid_method(update)]
+fn unstake(amount_e8s: u64) -> Result<Stake, Error> {
+    Access::with_state_mut(|state| state.unstake(&caller(), amount_e8s))
+}
+
+#[update]
+#[candid_method(update)]
+async fn withdraw_unstaked() -> Result<BlockIndex, Error> {
     let caller = caller();
-    let withdraw_info = Access::with_state_mut(|state| state.stage_payment(&caller))?;
-
-    let call_result = ic_ledger_types::transfer(
-        MAINNET_LEDGER_CANISTER_ID,
-        TransferArgs {
-            to: withdraw_info.deposit_address,
-            amount: Tokens::from_e8s(withdraw_info.amount),
-            fee: DEFAULT_FEE,
-            memo: Memo(0),
-            from_subaccount: None,
-            created_at_time: None,
+    let ledger_canister_id = Access::with_state(|state| state.staking_ledger_canister_id)
+        .ok_or(Error::StakingNotConfigured)?;
+    let fee = icrc1::fee(ledger_canister_id)
+        .await
+        .map_err(|(_, e)| Error::PaymentError(e))?;
+    let withdrawal = Access::with_state_mut(|state| {
+        state.stage_unstake_withdrawal(&caller, icrc1::nat_to_u64(&fee))
+    })?;
+
+    let result = icrc1::transfer(
+        ledger_canister_id,
+        icrc1::TransferArg {
+            from_subaccount: Some(withdrawal.from_subaccount),
+            to: withdrawal.to,
+            amount: Nat::from(withdrawal.amount_e8s - withdrawal.fee_e8s),
+            fee: Some(Nat::from(withdrawal.fee_e8s)),
+            memo: Some(withdrawal.memo()),
+            created_at_time: Some(withdrawal.created_at_time),
         },
     )
     .await;
+    let outcome = payments::icrc1_transfer_outcome(result);
 
-    Access::with_state_mut(|state| state.handle_payment_result(withdraw_info, call_result))
+    Access::with_state_mut(|state| {
+        state
+            .stakes
+            .finish_withdrawal(&withdrawal, &outcome, time())
+    });
+    match outcome {
+        TransferOutcome::Paid(block_index) => Ok(block_index),
+        TransferOutcome::Failed(e) => Err(Error::PaymentError(e)),
+        // the transfer may still have gone through, calling again retries it safely
+        TransferOutcome::Unknown(_) => Err(Error::PaymentInProgress),
+    }
 }
 
 // Queries
 #[query]
 #[candid_method(query)]
 fn get_proposals() -> Vec<Proposal> {
-    Access::with_state(|state| state.proposals.values().cloned().collect())
+    Access::with_state(|state| {
+        state
+            .proposals
+            .iter()
+            .map(|(_, proposal)| proposal)
+            .filter(|proposal| proposal.moderation_status != ModerationStatus::Hidden)
+            .collect()
+    })
 }
 
 #[query]
@@ -741,6 +2457,37 @@ fn get_proposals_info() -> Vec<ProposalInfo> {
     Access::with_state(|state| state.get_proposals_info(&caller()))
 }
 
+#[query]
+#[candid_method(query)]
+fn get_proposals_page(
+    sort: ProposalSort,
+    offset: u32,
+    limit: u32,
+    filter: Option<ProposalFilter>,
+) -> Vec<ProposalInfo> {
+    Access::with_state(|state| {
+        state.get_proposals_page(&caller(), sort, offset, limit, &filter.unwrap_or_default())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_round(round_id: u32, offset: u32, limit: u32) -> Result<RoundDetails, Error> {
+    Access::with_state(|state| state.get_round(round_id, offset, limit))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_rounds(offset: u32, limit: u32) -> Vec<RoundResult> {
+    Access::with_state(|state| state.get_rounds(offset, limit))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_user_earnings(user_id: u32, offset: u32, limit: u32) -> Vec<Earning> {
+    Access::with_state(|state| state.get_user_earnings(user_id, offset, limit))
+}
+
 #[query]
 #[candid_method(query)]
 fn get_users_by_karma(nr_of_users: u32) -> Vec<User> {
@@ -750,13 +2497,7 @@ fn get_users_by_karma(nr_of_users: u32) -> Vec<User> {
 #[query]
 #[candid_method(query)]
 fn get_user() -> Result<User, Error> {
-    Access::with_state(|state| {
-        state
-            .users
-            .get(&caller())
-            .cloned()
-            .ok_or(Error::UserDoesNotExist)
-    })
+    Access::with_state(|state| state.users.get(&caller()).ok_or(Error::UserDoesNotExist))
 }
 
 #[query]
@@ -776,11 +2517,29 @@ fn get_user_range(start: u32, end: u32) -> Vec<User> {
 fn get_changed_users(time_arg: Option<u64>) -> (Vec<(Principal, User)>, u64) {
     let now = time();
     Access::with_state(|state| {
-        let time = time_arg.unwrap_or_else(|| state.last_user_backup);
+        let time = time_arg.unwrap_or(state.last_user_backup);
         (state.users.get_changed_users(time), now)
     })
 }
 
+#[query]
+#[candid_method(query)]
+fn get_staking_account() -> Result<Account, Error> {
+    Access::with_state(|state| state.get_staking_account(&caller())).map(|(_, account)| account)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_staked_balance() -> Result<Stake, Error> {
+    Access::with_state(|state| state.get_staked_balance(&caller()))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_roles() -> Vec<(Principal, Vec<Role>)> {
+    Access::with_state(|state| state.roles.get_roles())
+}
+
 #[query]
 #[candid_method(query)]
 fn get_round_end_time_nano() -> u64 {
@@ -796,32 +2555,70 @@ fn poll_untweeted() -> Option<(usize, Tweet)> {
 #[query]
 #[candid_method(query)]
 fn get_canister_account() -> String {
-    let id = AccountIdentifier::new(&id(), &DEFAULT_SUBACCOUNT);
-    id.to_string()
+    AccountIdentifier::new(&id(), &DEFAULT_SUBACCOUNT).to_string()
+}
+
+/// Ledger the canister pays ICP rewards from, next to the account of `get_canister_account`.
+#[query]
+#[candid_method(query)]
+fn get_ledger_canister_id() -> Principal {
+    Access::with_state(|state| state.ledger_canister_id)
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    STATE.with(|cell| {
-        ciborium::ser::into_writer(cell.borrow().deref(), StableWriter::default())
-            .expect("failed to encode state")
-    })
+    let mut state_bytes = vec![];
+    Access::with_state(|state| {
+        ciborium::ser::into_writer(state, &mut state_bytes).expect("failed to encode state")
+    });
+
+    let mut memory = memory::get_upgrades_memory();
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(state_bytes.len() as u64).to_le_bytes())
+        .expect("failed to write state length");
+    writer
+        .write(&state_bytes)
+        .expect("failed to write state to stable memory");
 }
 
 #[post_upgrade]
-fn post_upgrade() {
-    STATE.with(|cell| {
-        let state =
+fn post_upgrade(args: Option<JohnDaoArgs>) {
+    let state = if memory::has_legacy_layout() {
+        let state: StateV0 =
             ciborium::de::from_reader(StableReader::default()).expect("failed to decode state");
-        *cell.borrow_mut() = state;
+        migrations::migrate_v0(state)
+    } else {
+        let memory = memory::get_upgrades_memory();
+        let mut reader = Reader::new(&memory, 0);
+        let mut state_len_bytes = [0; 8];
+        reader
+            .read(&mut state_len_bytes)
+            .expect("failed to read state length");
+        let mut state_bytes = vec![0; u64::from_le_bytes(state_len_bytes) as usize];
+        reader
+            .read(&mut state_bytes)
+            .expect("failed to read state from stable memory");
+        migrations::decode_state(&state_bytes)
+    };
+    STATE.with(|cell| *cell.borrow_mut() = Some(state));
+    Access::with_state_mut(|state| {
+        state.rebuild_proposal_index();
+        state.banned_patterns = BannedPatterns::compile(&state.content_policy.banned_patterns)
+            .unwrap_or_else(|_| ic_cdk::trap("failed to compile the banned patterns"));
     });
 
-    Access::with_state_mut(|state| {
-        let new_duration = Duration::from_secs(3600 * 25);
-        state.settings.round_duration_seconds = 3600 * 25;
-        state.round_end_time = time() + sec_to_nanos(state.settings.round_duration_seconds);
-        set_timer_recursive(new_duration);
-    })
+    match args {
+        Some(JohnDaoArgs::Upgrade(args)) => {
+            Access::with_state_mut(|state| state.apply_upgrade_args(args))
+        }
+        Some(JohnDaoArgs::Init(_)) => ic_cdk::trap("expected upgrade arguments"),
+        None => {}
+    }
+
+    schedule_round_end();
+    schedule_payment_reconciliation();
+    schedule_treasury_refresh();
 }
 
 #[cfg(test)]
@@ -833,10 +2630,24 @@ mod tests {
     fn save_candid() {
         use std::env;
         use std::fs::write;
-        use std::path::PathBuf;
 
-        let dir = PathBuf::from(env::current_dir().unwrap());
+        let dir = env::current_dir().unwrap();
         export_service!();
         write(dir.join("john_dao.did"), __export_service()).expect("Write failed.");
     }
+
+    #[test]
+    fn carries_over_points() {
+        assert_eq!(CarryOverPolicy::None.carried_points(5, 0), None);
+        assert_eq!(CarryOverPolicy::ResetToOne.carried_points(5, 3), Some(1));
+        assert_eq!(CarryOverPolicy::ResetToOne.carried_points(0, 0), None);
+        assert_eq!(CarryOverPolicy::Full.carried_points(5, 3), Some(5));
+        assert_eq!(CarryOverPolicy::Full.carried_points(-2, 0), None);
+        let decay = CarryOverPolicy::Decay { percent_kept: 50 };
+        assert_eq!(decay.carried_points(5, 0), Some(2));
+        assert_eq!(decay.carried_points(1, 0), None);
+        let max_age = CarryOverPolicy::MaxAge { max_rounds: 2 };
+        assert_eq!(max_age.carried_points(5, 1), Some(5));
+        assert_eq!(max_age.carried_points(5, 2), None);
+    }
 }
@@ -0,0 +1,125 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Blob,
+    DefaultMemoryImpl, Memory as _,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+pub type StablePrincipal = Blob<29>;
+
+const WASM_PAGE_SIZE_BYTES: u64 = 65536;
+
+// the first bytes of stable memory once the memory manager owns it
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+const UPGRADES: MemoryId = MemoryId::new(0);
+const USERS: MemoryId = MemoryId::new(1);
+const USER_PRINCIPALS: MemoryId = MemoryId::new(2);
+const PROPOSALS: MemoryId = MemoryId::new(3);
+const ROUND_RESULTS: MemoryId = MemoryId::new(4);
+const PAYMENT_HISTORY_INDEX: MemoryId = MemoryId::new(5);
+const PAYMENT_HISTORY_DATA: MemoryId = MemoryId::new(6);
+const STAKES: MemoryId = MemoryId::new(7);
+const DONATIONS: MemoryId = MemoryId::new(8);
+const DONOR_TOTALS: MemoryId = MemoryId::new(9);
+const EARNINGS: MemoryId = MemoryId::new(10);
+const STANDINGS: MemoryId = MemoryId::new(11);
+const WINNER_TRIGRAMS: MemoryId = MemoryId::new(12);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+pub fn get_upgrades_memory() -> Memory {
+    get_memory(UPGRADES)
+}
+
+pub fn get_users_memory() -> Memory {
+    get_memory(USERS)
+}
+
+pub fn get_user_principals_memory() -> Memory {
+    get_memory(USER_PRINCIPALS)
+}
+
+pub fn get_proposals_memory() -> Memory {
+    get_memory(PROPOSALS)
+}
+
+pub fn get_round_results_memory() -> Memory {
+    get_memory(ROUND_RESULTS)
+}
+
+pub fn get_payment_history_memories() -> (Memory, Memory) {
+    (
+        get_memory(PAYMENT_HISTORY_INDEX),
+        get_memory(PAYMENT_HISTORY_DATA),
+    )
+}
+
+pub fn get_stakes_memory() -> Memory {
+    get_memory(STAKES)
+}
+
+pub fn get_donations_memory() -> Memory {
+    get_memory(DONATIONS)
+}
+
+pub fn get_donor_totals_memory() -> Memory {
+    get_memory(DONOR_TOTALS)
+}
+
+pub fn get_earnings_memory() -> Memory {
+    get_memory(EARNINGS)
+}
+
+pub fn get_standings_memory() -> Memory {
+    get_memory(STANDINGS)
+}
+
+pub fn get_winner_trigrams_memory() -> Memory {
+    get_memory(WINNER_TRIGRAMS)
+}
+
+/// Returns true if stable memory still holds the state written by a version of the
+/// canister that serialized everything with ciborium in pre_upgrade.
+/// Must be called before any stable structure is initialized.
+pub fn has_legacy_layout() -> bool {
+    let memory = DefaultMemoryImpl::default();
+    if memory.size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    memory.read(0, &mut magic);
+    &magic != MEMORY_MANAGER_MAGIC
+}
+
+pub fn stable_memory_size_bytes() -> u64 {
+    DefaultMemoryImpl::default().size() * WASM_PAGE_SIZE_BYTES
+}
+
+pub fn to_principal(principal: &candid::Principal) -> StablePrincipal {
+    StablePrincipal::try_from(principal.as_slice()).expect("principal longer than 29 bytes")
+}
+
+pub fn from_principal(principal: &StablePrincipal) -> candid::Principal {
+    candid::Principal::from_slice(principal.as_slice())
+}
+
+pub fn encode<T: Serialize>(value: &T) -> Cow<'static, [u8]> {
+    let mut bytes = vec![];
+    ciborium::ser::into_writer(value, &mut bytes).expect("failed to encode value");
+    Cow::Owned(bytes)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> T {
+    ciborium::de::from_reader(bytes).expect("failed to decode value")
+}
@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use candid::Principal;
+use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    content_policy::ContentPolicy,
+    content_policy::{BannedPatterns, Trigrams},
+    donations::Donations,
+    init_payment_history, init_proposals, init_round_results, init_standings, init_winner_trigrams,
+    mainnet_ledger_canister_id,
+    memory::decode,
+    proposal_index::ProposalIndex,
+    rewards::Earnings,
+    roles::Roles,
+    staking::Stakes,
+    treasury::Treasury,
+    users::{User, Users},
+    Payment, Proposal, RewardToken, RoundResult, Settings, State,
+};
+
+// principal that was hardcoded as the only admin before roles were kept in state
+const LEGACY_ADMIN: &str = "25xau-y2lix-xnu4a-gfgnv-nsm4l-5vsde-3hsvd-ol3o6-qwihp-5crn7-sqe";
+
+/// Layout of the state persisted across upgrades.
+///
+/// V0: the whole state serialized with ciborium straight into stable memory.
+/// V1: users, proposals, round results and payments in stable structures,
+///     the rest serialized into the upgrades memory without a version tag.
+/// V2: version tag added, `State.test` and `User.created_proposal` dropped.
+/// V3: `State.reward_token` moved into `Settings`.
+/// V4: `Treasury` keeps running withdrawable totals, computed once from the users.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum StateVersion {
+    V0,
+    // the version tag was introduced in V2, anything in the upgrades memory without it is V1
+    #[default]
+    V1,
+    V2,
+    V3,
+    V4,
+}
+
+pub const CURRENT_VERSION: StateVersion = StateVersion::V4;
+
+#[derive(Deserialize)]
+struct VersionTag {
+    #[serde(default)]
+    version: StateVersion,
+}
+
+#[derive(Deserialize)]
+struct UsersV0 {
+    users: HashMap<Principal, User>,
+}
+
+#[derive(Deserialize)]
+pub struct StateV0 {
+    next_user_id: u32,
+    users: UsersV0,
+    next_proposal_id: u32,
+    proposals: HashMap<u32, Proposal>,
+    round_results: Vec<RoundResult>,
+    payment_history: Vec<Payment>,
+    settings: Settings,
+    round_end_time: u64,
+    last_user_backup: u64,
+    #[serde(default)]
+    roles: Roles,
+}
+
+#[derive(Deserialize)]
+pub struct StateV1 {
+    next_user_id: u32,
+    next_proposal_id: u32,
+    settings: Settings,
+    round_end_time: u64,
+    last_user_backup: u64,
+    roles: Roles,
+}
+
+#[derive(Deserialize)]
+pub struct StateV2 {
+    next_user_id: u32,
+    next_proposal_id: u32,
+    settings: Settings,
+    round_end_time: u64,
+    last_user_backup: u64,
+    roles: Roles,
+    #[serde(default = "mainnet_ledger_canister_id")]
+    ledger_canister_id: Principal,
+    #[serde(default)]
+    reward_token: RewardToken,
+}
+
+impl StateV0 {
+    fn into_v1(self) -> StateV1 {
+        // V0 only kept the votes on the users
+        let mut vote_counts = HashMap::<u32, (u32, u32)>::new();
+        for user in self.users.users.values() {
+            for proposal_id in &user.upvotes {
+                vote_counts.entry(*proposal_id).or_default().0 += 1;
+            }
+            for proposal_id in &user.downvotes {
+                vote_counts.entry(*proposal_id).or_default().1 += 1;
+            }
+        }
+
+        let mut users = Users::new();
+        for (principal, user) in self.users.users {
+            users.insert(principal, user);
+        }
+
+        let mut proposals = init_proposals();
+        for (id, mut proposal) in self.proposals {
+            (proposal.nr_of_upvotes, proposal.nr_of_downvotes) =
+                vote_counts.get(&id).copied().unwrap_or_default();
+            proposals.insert(id, proposal);
+        }
+
+        let mut round_results = init_round_results();
+        let mut winner_trigrams = init_winner_trigrams();
+        for (index, round_result) in self.round_results.into_iter().enumerate() {
+            // V0 rounds have a single winner
+            if let Some(proposal) = &round_result.winning_proposal {
+                winner_trigrams.insert((index as u32, 0), Trigrams::of(proposal.tweet.get_tweet()));
+            }
+            round_results.insert(index as u32, round_result);
+        }
+
+        let payment_history = init_payment_history();
+        for payment in &self.payment_history {
+            payment_history
+                .append(payment)
+                .expect("failed to append payment to history");
+        }
+
+        let roles = if self.roles.is_empty() {
+            Roles::with_admins(vec![Principal::from_text(LEGACY_ADMIN).unwrap()])
+        } else {
+            self.roles
+        };
+
+        StateV1 {
+            next_user_id: self.next_user_id,
+            next_proposal_id: self.next_proposal_id,
+            settings: self.settings,
+            round_end_time: self.round_end_time,
+            last_user_backup: self.last_user_backup,
+            roles,
+        }
+    }
+}
+
+impl StateV1 {
+    fn into_v2(self) -> StateV2 {
+        // rewrite every user so the dropped `created_proposal` field is gone from stable memory
+        Users::new().reencode_all();
+
+        StateV2 {
+            next_user_id: self.next_user_id,
+            next_proposal_id: self.next_proposal_id,
+            settings: self.settings,
+            round_end_time: self.round_end_time,
+            last_user_backup: self.last_user_backup,
+            roles: self.roles,
+            ledger_canister_id: MAINNET_LEDGER_CANISTER_ID,
+            reward_token: RewardToken::Icp,
+        }
+    }
+}
+
+impl StateV2 {
+    fn into_v3(self) -> State {
+        let mut settings = self.settings;
+        settings.reward_token = self.reward_token;
+
+        State {
+            version: StateVersion::V3,
+            next_user_id: self.next_user_id,
+            users: Users::new(),
+            next_proposal_id: self.next_proposal_id,
+            proposals: init_proposals(),
+            round_results: init_round_results(),
+            standings: init_standings(),
+            untweeted_rounds: BTreeSet::new(),
+            payment_history: init_payment_history(),
+            settings,
+            round_end_time: self.round_end_time,
+            last_user_backup: self.last_user_backup,
+            roles: self.roles,
+            ledger_canister_id: self.ledger_canister_id,
+            stakes: Stakes::new(),
+            staking_ledger_canister_id: None,
+            next_payment_id: 0,
+            pending_payments: BTreeMap::new(),
+            treasury: Treasury::default(),
+            donations: Donations::new(),
+            earnings: Earnings::new(),
+            reward_carry_over_e8s: 0,
+            proposal_index: ProposalIndex::default(),
+            content_policy: ContentPolicy::default(),
+            banned_patterns: BannedPatterns::default(),
+            winner_trigrams: init_winner_trigrams(),
+        }
+    }
+}
+
+impl State {
+    fn into_v4(mut self) -> State {
+        let users = self.users.get_users();
+        self.treasury.recompute_withdrawable(&users);
+
+        self.version = StateVersion::V4;
+        self
+    }
+}
+
+/// Migrates a state read from the pre stable structures layout.
+pub fn migrate_v0(state: StateV0) -> State {
+    let untweeted_rounds = state
+        .round_results
+        .iter()
+        .enumerate()
+        .filter(|(_, round_result)| !round_result.proposal_tweeted)
+        .map(|(index, _)| index as u32)
+        .collect();
+    let mut state = state.into_v1().into_v2().into_v3().into_v4();
+    state.untweeted_rounds = untweeted_rounds;
+    state
+}
+
+/// Decodes the state from the upgrades memory, migrating it step by step if it was
+/// written by an older version.
+pub fn decode_state(bytes: &[u8]) -> State {
+    let tag: VersionTag = decode(bytes);
+    match tag.version {
+        StateVersion::V0 => panic!("V0 state is never written to the upgrades memory"),
+        StateVersion::V1 => decode::<StateV1>(bytes).into_v2().into_v3().into_v4(),
+        StateVersion::V2 => decode::<StateV2>(bytes).into_v3().into_v4(),
+        StateVersion::V3 => decode::<State>(bytes).into_v4(),
+        StateVersion::V4 => decode(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::encode, roles::Role};
+
+    const STATE_V0: &[u8] = include_bytes!("../fixtures/state_v0.cbor");
+    const STATE_V1: &[u8] = include_bytes!("../fixtures/state_v1.cbor");
+    const USER_V1: &[u8] = include_bytes!("../fixtures/user_v1.cbor");
+    const STATE_V2: &[u8] = include_bytes!("../fixtures/state_v2.cbor");
+
+    #[test]
+    fn migrates_v0_state_into_stable_structures() {
+        let state = migrate_v0(decode(STATE_V0));
+
+        assert_eq!(state.version, CURRENT_VERSION);
+        assert_eq!(state.next_user_id, 3);
+        assert_eq!(state.next_proposal_id, 2);
+        assert_eq!(state.settings.round_duration_seconds, 90_000);
+        assert_eq!(state.round_end_time, 1_000);
+        assert_eq!(state.last_user_backup, 500);
+
+        let alice = state.users.get(&Principal::from_slice(&[1; 29])).unwrap();
+        assert_eq!(alice.id, 1);
+        assert_eq!(alice.karma, 25);
+        assert!(alice.deposit_address.is_some());
+        assert_eq!(state.users.get_by_id(2).unwrap().karma, 10);
+
+        let proposal = state.proposals.get(&1).unwrap();
+        assert_eq!(proposal.points, 3);
+        assert_eq!((proposal.nr_of_upvotes, proposal.nr_of_downvotes), (2, 0));
+        assert!(state.round_results.get(&0).unwrap().proposal_tweeted);
+        assert!(state.untweeted_rounds.is_empty());
+        assert_eq!(state.winner_trigrams.len(), 1);
+        assert_eq!(state.payment_history.get(0).unwrap().block_index, 5);
+
+        let legacy_admin = Principal::from_text(LEGACY_ADMIN).unwrap();
+        assert!(state.roles.has_role(&legacy_admin, Role::Admin));
+    }
+
+    #[test]
+    fn migrates_v1_state() {
+        let state = decode_state(STATE_V1);
+
+        assert_eq!(state.version, CURRENT_VERSION);
+        assert_eq!(state.next_user_id, 3);
+        assert_eq!(state.settings.reward_per_round_e8s, 10_000_000);
+        let bob = Principal::from_slice(&[2; 29]);
+        assert!(state.roles.has_role(&bob, Role::TweetPoster));
+        assert_eq!(state.roles.nr_of_admins(), 1);
+    }
+
+    #[test]
+    fn drops_created_proposal_from_v1_users() {
+        let user: User = decode(USER_V1);
+        assert_eq!(user.karma, 25);
+        assert_eq!(user.user_name.as_deref(), Some("user1"));
+        assert!(user.upvotes.contains(&1));
+
+        let principal = Principal::from_slice(&[1; 29]);
+        Users::new().insert(principal, user);
+        let state = decode_state(STATE_V1);
+
+        let user = state.users.get(&principal).unwrap();
+        assert_eq!(user.id, 1);
+        let field = b"created_proposal";
+        assert!(USER_V1.windows(field.len()).any(|window| window == field));
+        assert!(!encode(&user)
+            .windows(field.len())
+            .any(|window| window == field));
+    }
+
+    #[test]
+    fn moves_v2_reward_token_into_settings() {
+        let state = decode_state(STATE_V2);
+
+        assert_eq!(state.version, CURRENT_VERSION);
+        assert_eq!(state.next_user_id, 3);
+        let ledger_canister_id = Principal::from_slice(&[3; 10]);
+        assert_eq!(state.ledger_canister_id, ledger_canister_id);
+        assert!(state.settings.reward_token == RewardToken::Icrc1 { ledger_canister_id });
+    }
+
+    #[test]
+    fn current_state_round_trips() {
+        let state = decode_state(STATE_V1);
+        let bytes = encode(&state);
+        let state = decode_state(&bytes);
+        assert_eq!(state.version, CURRENT_VERSION);
+        assert_eq!(state.next_proposal_id, 2);
+    }
+}
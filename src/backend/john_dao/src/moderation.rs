@@ -0,0 +1,189 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::users::{User, VerificationStatus};
+
+// weight of a report by an unverified user without karma or report history, report weights
+// are in percent of it
+pub const ORDINARY_REPORT_WEIGHT: u32 = 100;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ReportReason {
+    Spam,
+    Offensive,
+    Misleading,
+    Duplicate,
+    #[default]
+    Other,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum ModerationStatus {
+    // fewer reports than the threshold, or not reviewed yet
+    #[default]
+    Visible,
+    // reached the report threshold, hidden until a moderator reviews it
+    Hidden,
+    // a moderator kept the proposal, further reports no longer hide it
+    Approved,
+}
+
+impl ModerationStatus {
+    /// The status after a report, `report_weight` includes the new one and
+    /// `report_threshold` is in ordinary reports. A threshold of 0 never hides anything.
+    pub fn after_report(self, report_weight: u32, report_threshold: u32) -> Self {
+        let threshold = u64::from(report_threshold) * u64::from(ORDINARY_REPORT_WEIGHT);
+        match self {
+            ModerationStatus::Visible if threshold > 0 && u64::from(report_weight) >= threshold => {
+                ModerationStatus::Hidden
+            }
+            status => status,
+        }
+    }
+}
+
+// how much a report counts, in percent of an ordinary report
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct ReportWeighting {
+    pub unverified_percent: u32,
+    pub verified_percent: u32,
+    // every this much karma adds a percent, 0 disables karma weighting
+    pub karma_per_extra_percent: u32,
+    pub max_karma_percent: u32,
+}
+
+impl Default for ReportWeighting {
+    fn default() -> Self {
+        ReportWeighting {
+            unverified_percent: ORDINARY_REPORT_WEIGHT,
+            verified_percent: 150,
+            karma_per_extra_percent: 10,
+            max_karma_percent: 100,
+        }
+    }
+}
+
+impl ReportWeighting {
+    /// Weight of a report by `reporter`. Reporters whose reports moderators keep approving
+    /// count for less, down to nothing.
+    pub fn weight(&self, reporter: &User) -> u32 {
+        let base_percent = match reporter.verification_status {
+            VerificationStatus::Unverified => self.unverified_percent,
+            VerificationStatus::Verified => self.verified_percent,
+            VerificationStatus::Bot => 0,
+        };
+        let karma_percent = (reporter.karma.max(0) as u32)
+            .checked_div(self.karma_per_extra_percent)
+            .unwrap_or_default()
+            .min(self.max_karma_percent);
+        let weight = u64::from(base_percent) + u64::from(karma_percent);
+        let upheld = u64::from(reporter.reports_upheld);
+        let rejected = u64::from(reporter.reports_rejected);
+        (weight * (upheld + 1) / (upheld + rejected + 1)) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+    use crate::users::LoginMethod;
+
+    fn reporter(
+        verification_status: VerificationStatus,
+        karma: i32,
+        reports_upheld: u32,
+        reports_rejected: u32,
+    ) -> User {
+        User {
+            id: 1,
+            user_name: None,
+            login_method: LoginMethod::II,
+            badges: Vec::new(),
+            new_auth: None,
+            karma,
+            withdrawable_e8s: 0,
+            withdrawable_tokens: HashMap::new(),
+            deposit_address: None,
+            icrc1_account: None,
+            payment_in_progress: false,
+            upvotes: HashSet::new(),
+            downvotes: HashSet::new(),
+            vote_weights: HashMap::new(),
+            vote_credits_used: 0,
+            unpaid_credits: Vec::new(),
+            verification_status,
+            last_updated: 0,
+            nr_posts_this_round: 0,
+            nr_reports_this_round: 0,
+            reports_upheld,
+            reports_rejected,
+        }
+    }
+
+    #[test]
+    fn weighs_reports_by_reputation() {
+        let weighting = ReportWeighting::default();
+        let weight = |user: &User| weighting.weight(user);
+
+        assert_eq!(
+            weight(&reporter(VerificationStatus::Unverified, 0, 0, 0)),
+            100
+        );
+        assert_eq!(
+            weight(&reporter(VerificationStatus::Verified, 0, 0, 0)),
+            150
+        );
+        assert_eq!(weight(&reporter(VerificationStatus::Bot, 500, 0, 0)), 50);
+        assert_eq!(
+            weight(&reporter(VerificationStatus::Unverified, -50, 0, 0)),
+            100
+        );
+        assert_eq!(
+            weight(&reporter(VerificationStatus::Unverified, 250, 0, 0)),
+            125
+        );
+        // karma adds at most `max_karma_percent`
+        assert_eq!(
+            weight(&reporter(VerificationStatus::Unverified, 5_000, 0, 0)),
+            200
+        );
+        // rejected reports scale the weight down, upheld ones make up for them
+        assert_eq!(
+            weight(&reporter(VerificationStatus::Unverified, 0, 0, 1)),
+            50
+        );
+        assert_eq!(
+            weight(&reporter(VerificationStatus::Unverified, 0, 1, 1)),
+            66
+        );
+        assert_eq!(
+            weight(&reporter(VerificationStatus::Unverified, 0, 3, 0)),
+            100
+        );
+
+        let without_karma = ReportWeighting {
+            karma_per_extra_percent: 0,
+            ..ReportWeighting::default()
+        };
+        let user = reporter(VerificationStatus::Unverified, 5_000, 0, 0);
+        assert_eq!(without_karma.weight(&user), 100);
+    }
+
+    #[test]
+    fn hides_proposals_at_the_report_threshold() {
+        let visible = ModerationStatus::Visible;
+        assert_eq!(visible.after_report(499, 5), ModerationStatus::Visible);
+        assert_eq!(visible.after_report(500, 5), ModerationStatus::Hidden);
+        assert_eq!(visible.after_report(u32::MAX, 0), ModerationStatus::Visible);
+        assert_eq!(
+            ModerationStatus::Approved.after_report(u32::MAX, 1),
+            ModerationStatus::Approved
+        );
+        assert_eq!(
+            ModerationStatus::Hidden.after_report(0, 5),
+            ModerationStatus::Hidden
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use ic_ledger_types::{
+    AccountIdentifier, BlockIndex, Memo, Timestamp, Tokens, TransferArgs, TransferError,
+    DEFAULT_FEE,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    icrc1::{self, Account},
+    Error, RewardToken,
+};
+
+// a pending payment is retried for at most this long, the ledgers only deduplicate
+// transactions created within the last 24 hours
+pub const MAX_RETRY_WINDOW_NANOS: u64 = 20 * 3600 * 1_000_000_000;
+// a pending payment that was attempted more recently than this is assumed to still be in flight
+pub const RETRY_AFTER_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub enum PaymentTarget {
+    Icp {
+        ledger_canister_id: Principal,
+        to: AccountIdentifier,
+    },
+    Icrc1 {
+        ledger_canister_id: Principal,
+        to: Account,
+    },
+}
+
+/// A withdrawal that was taken from the user's balance but is not yet known to be on the
+/// ledger. The memo and created_at_time stay the same for every attempt so the ledger
+/// rejects retries of a transfer that already went through as duplicates.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct PendingPayment {
+    pub id: u64,
+    pub user_id: u32,
+    // amount that reaches the user
+    pub amount: u64,
+    pub fee: u64,
+    // amount taken from the user's balance, credited back if the payment fails
+    pub withdrawn_e8s: u64,
+    pub token: RewardToken,
+    pub target: PaymentTarget,
+    pub created_at_time: u64,
+    pub last_attempt: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+pub enum TransferOutcome {
+    // the transfer is on the ledger
+    Paid(BlockIndex),
+    // the ledger did not execute the transfer
+    Failed(String),
+    // the transfer may or may not have been executed, retry with the same arguments
+    Unknown(String),
+}
+
+fn is_ambiguous(rejection_code: RejectionCode) -> bool {
+    matches!(
+        rejection_code,
+        RejectionCode::SysTransient | RejectionCode::SysFatal | RejectionCode::Unknown
+    )
+}
+
+/// Fee charged by the ledger of `token` for a transfer.
+pub async fn fee(token: &RewardToken) -> Result<u64, Error> {
+    match token {
+        RewardToken::Icp => Ok(DEFAULT_FEE.e8s()),
+        RewardToken::Icrc1 { ledger_canister_id } => icrc1::fee(*ledger_canister_id)
+            .await
+            .map(|fee| icrc1::nat_to_u64(&fee))
+            .map_err(|(_, e)| Error::PaymentError(e)),
+    }
+}
+
+pub async fn transfer(payment: &PendingPayment) -> TransferOutcome {
+    match &payment.target {
+        PaymentTarget::Icp {
+            ledger_canister_id,
+            to,
+        } => {
+            let result = ic_ledger_types::transfer(
+                *ledger_canister_id,
+                TransferArgs {
+                    to: *to,
+                    amount: Tokens::from_e8s(payment.amount),
+                    fee: Tokens::from_e8s(payment.fee),
+                    memo: Memo(payment.id),
+                    from_subaccount: None,
+                    created_at_time: Some(Timestamp {
+                        timestamp_nanos: payment.created_at_time,
+                    }),
+                },
+            )
+            .await;
+            match result {
+                Ok(Ok(block_index)) => TransferOutcome::Paid(block_index),
+                Ok(Err(TransferError::TxDuplicate { duplicate_of })) => {
+                    TransferOutcome::Paid(duplicate_of)
+                }
+                Ok(Err(e @ TransferError::TxTooOld { .. }))
+                | Ok(Err(e @ TransferError::TxCreatedInFuture)) => {
+                    TransferOutcome::Unknown(e.to_string())
+                }
+                Ok(Err(e)) => TransferOutcome::Failed(e.to_string()),
+                Err((code, e)) if is_ambiguous(code) => TransferOutcome::Unknown(e),
+                Err((_, e)) => TransferOutcome::Failed(e),
+            }
+        }
+        PaymentTarget::Icrc1 {
+            ledger_canister_id,
+            to,
+        } => {
+            let result = icrc1::transfer(
+                *ledger_canister_id,
+                icrc1::TransferArg {
+                    from_subaccount: None,
+                    to: *to,
+                    amount: Nat::from(payment.amount),
+                    fee: Some(Nat::from(payment.fee)),
+                    memo: Some(payment.id.to_be_bytes().to_vec()),
+                    created_at_time: Some(payment.created_at_time),
+                },
+            )
+            .await;
+            icrc1_transfer_outcome(result)
+        }
+    }
+}
+
+/// Outcome of an ICRC-1 transfer that always carries a memo and `created_at_time`, so
+/// retrying it with the same arguments can not pay twice.
+pub fn icrc1_transfer_outcome(
+    result: CallResult<Result<Nat, icrc1::TransferError>>,
+) -> TransferOutcome {
+    match result {
+        Ok(Ok(block_index)) => TransferOutcome::Paid(icrc1::nat_to_u64(&block_index)),
+        Ok(Err(icrc1::TransferError::Duplicate { duplicate_of })) => {
+            TransferOutcome::Paid(icrc1::nat_to_u64(&duplicate_of))
+        }
+        Ok(Err(
+            e @ (icrc1::TransferError::TooOld
+            | icrc1::TransferError::CreatedInFuture { .. }
+            | icrc1::TransferError::TemporarilyUnavailable),
+        )) => TransferOutcome::Unknown(format!("{:?}", e)),
+        Ok(Err(e)) => TransferOutcome::Failed(format!("{:?}", e)),
+        Err((code, e)) if is_ambiguous(code) => TransferOutcome::Unknown(e),
+        Err((_, e)) => TransferOutcome::Failed(e),
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+};
+
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::{content_policy::Trigrams, moderation::ModerationStatus, Proposal, Tweet};
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProposalSort {
+    // points weighed against age, a newer proposal needs fewer points to rank as high
+    Hot,
+    Top,
+    New,
+    // proposals with many up- and downvotes that are close to even
+    Controversial,
+}
+
+const SORTS: [ProposalSort; 4] = [
+    ProposalSort::Hot,
+    ProposalSort::Top,
+    ProposalSort::New,
+    ProposalSort::Controversial,
+];
+
+// a proposal with ten times the points ranks as high as one created this much later
+const HOT_SECONDS_PER_ORDER: f64 = 45_000.0;
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ProposalFilter {
+    pub created_by: Option<u32>,
+    pub voted_by_me: bool,
+    pub reported_by_me: bool,
+    // only proposals with an image
+    pub media_only: bool,
+}
+
+// highest score first, ties go to the earliest proposal, then the lowest id
+type SortKey = (Reverse<i64>, u64, u32);
+
+#[derive(Clone, Copy)]
+struct Entry {
+    points: i32,
+    created_at: u64,
+    created_by: u32,
+    has_media: bool,
+    // waiting for a moderator, left out of every page
+    hidden: bool,
+    nr_of_upvotes: u32,
+    nr_of_downvotes: u32,
+}
+
+impl Entry {
+    fn sort_key(&self, sort: ProposalSort, id: u32) -> SortKey {
+        let score = match sort {
+            ProposalSort::Hot => hot_score(self.points, self.created_at),
+            ProposalSort::Top => i64::from(self.points),
+            ProposalSort::New => self.created_at as i64,
+            ProposalSort::Controversial => {
+                controversy_score(self.nr_of_upvotes, self.nr_of_downvotes)
+            }
+        };
+        (Reverse(score), self.created_at, id)
+    }
+}
+
+fn hot_score(points: i32, created_at: u64) -> i64 {
+    let order = f64::from(points.unsigned_abs().max(1)).log10() * f64::from(points.signum());
+    let age_orders = (created_at / 1_000_000_000) as f64 / HOT_SECONDS_PER_ORDER;
+    ((order + age_orders) * 1_000_000.0) as i64
+}
+
+fn controversy_score(nr_of_upvotes: u32, nr_of_downvotes: u32) -> i64 {
+    if nr_of_upvotes == 0 || nr_of_downvotes == 0 {
+        return 0;
+    }
+    let magnitude = f64::from(nr_of_upvotes + nr_of_downvotes);
+    let balance = f64::from(nr_of_upvotes.min(nr_of_downvotes))
+        / f64::from(nr_of_upvotes.max(nr_of_downvotes));
+    (magnitude.powf(balance) * 1_000_000.0) as i64
+}
+
+fn intersect(candidates: Option<HashSet<u32>>, ids: impl Iterator<Item = u32>) -> HashSet<u32> {
+    match candidates {
+        Some(candidates) => ids.filter(|id| candidates.contains(id)).collect(),
+        None => ids.collect(),
+    }
+}
+
+/// Orders the proposals of the current round for every sort, so a page only decodes the
+/// proposals on it, and keeps the trigrams of their tweets for the duplicate check. Lives
+/// on the heap, it is rebuilt after upgrades and when a round ends.
+#[derive(Default)]
+pub struct ProposalIndex {
+    entries: HashMap<u32, Entry>,
+    trigrams: HashMap<u32, Trigrams>,
+    sorted: HashMap<ProposalSort, BTreeSet<SortKey>>,
+    by_author: BTreeSet<(u32, u32)>,
+    reported_by: BTreeSet<(Principal, u32)>,
+}
+
+impl ProposalIndex {
+    pub fn build(proposals: impl Iterator<Item = (u32, Proposal)>) -> Self {
+        let mut index = Self::default();
+        for (id, proposal) in proposals {
+            index.insert(id, &proposal);
+        }
+        index
+    }
+
+    pub fn insert(&mut self, id: u32, proposal: &Proposal) {
+        self.remove(id);
+        self.trigrams
+            .insert(id, Trigrams::of(proposal.tweet.get_tweet()));
+        self.by_author.insert((proposal.created_by_id, id));
+        for reporter in &proposal.reports {
+            self.reported_by.insert((*reporter, id));
+        }
+        self.insert_entry(
+            id,
+            Entry {
+                points: proposal.points,
+                created_at: proposal.created_at,
+                created_by: proposal.created_by_id,
+                has_media: matches!(proposal.tweet, Tweet::ImageTweet { .. }),
+                hidden: proposal.moderation_status == ModerationStatus::Hidden,
+                nr_of_upvotes: proposal.nr_of_upvotes,
+                nr_of_downvotes: proposal.nr_of_downvotes,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        if let Some(entry) = self.remove_entry(id) {
+            self.trigrams.remove(&id);
+            self.by_author.remove(&(entry.created_by, id));
+            self.reported_by
+                .retain(|(_, proposal_id)| *proposal_id != id);
+        }
+    }
+
+    pub fn record_report(&mut self, id: u32, reporter: Principal) {
+        if self.entries.contains_key(&id) {
+            self.reported_by.insert((reporter, id));
+        }
+    }
+
+    pub fn set_hidden(&mut self, id: u32, hidden: bool) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.hidden = hidden;
+        }
+    }
+
+    /// Updates the points and vote counts of a proposal after a vote.
+    pub fn record_vote(&mut self, id: u32, proposal: &Proposal) {
+        let Some(mut entry) = self.remove_entry(id) else {
+            return;
+        };
+        entry.points = proposal.points;
+        entry.nr_of_upvotes = proposal.nr_of_upvotes;
+        entry.nr_of_downvotes = proposal.nr_of_downvotes;
+        self.insert_entry(id, entry);
+    }
+
+    pub fn trigrams(&self) -> impl Iterator<Item = (u32, &Trigrams)> {
+        self.trigrams.iter().map(|(id, trigrams)| (*id, trigrams))
+    }
+
+    /// Ids of the proposals that pass `filter`, in the order of `sort`. `voted_by_caller`
+    /// holds the proposals the caller up- or downvoted.
+    pub fn page(
+        &self,
+        sort: ProposalSort,
+        filter: &ProposalFilter,
+        caller: &Principal,
+        voted_by_caller: &HashSet<u32>,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<u32> {
+        let mut candidates = None;
+        if let Some(created_by) = filter.created_by {
+            let ids = self
+                .by_author
+                .range((created_by, 0)..=(created_by, u32::MAX))
+                .map(|(_, id)| *id);
+            candidates = Some(intersect(candidates, ids));
+        }
+        if filter.reported_by_me {
+            let ids = self
+                .reported_by
+                .range((*caller, 0)..=(*caller, u32::MAX))
+                .map(|(_, id)| *id);
+            candidates = Some(intersect(candidates, ids));
+        }
+        if filter.voted_by_me {
+            candidates = Some(intersect(candidates, voted_by_caller.iter().copied()));
+        }
+        let passes = |entry: &Entry| !entry.hidden && (!filter.media_only || entry.has_media);
+
+        match candidates {
+            // the filters already narrowed the proposals down, only those get sorted
+            Some(ids) => {
+                let mut keys = ids
+                    .into_iter()
+                    .filter_map(|id| {
+                        let entry = self.entries.get(&id)?;
+                        passes(entry).then(|| entry.sort_key(sort, id))
+                    })
+                    .collect::<Vec<_>>();
+                keys.sort();
+                keys.into_iter()
+                    .map(|(_, _, id)| id)
+                    .skip(offset)
+                    .take(limit)
+                    .collect()
+            }
+            None => self
+                .sorted
+                .get(&sort)
+                .into_iter()
+                .flatten()
+                .map(|(_, _, id)| *id)
+                .filter(|id| self.entries.get(id).is_some_and(passes))
+                .skip(offset)
+                .take(limit)
+                .collect(),
+        }
+    }
+
+    fn insert_entry(&mut self, id: u32, entry: Entry) {
+        for sort in SORTS {
+            self.sorted
+                .entry(sort)
+                .or_default()
+                .insert(entry.sort_key(sort, id));
+        }
+        self.entries.insert(id, entry);
+    }
+
+    fn remove_entry(&mut self, id: u32) -> Option<Entry> {
+        let entry = self.entries.remove(&id)?;
+        for sort in SORTS {
+            if let Some(keys) = self.sorted.get_mut(&sort) {
+                keys.remove(&entry.sort_key(sort, id));
+            }
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposal(
+        created_by_id: u32,
+        points: i32,
+        created_at: u64,
+        image: bool,
+        (nr_of_upvotes, nr_of_downvotes): (u32, u32),
+    ) -> Proposal {
+        let tweet = if image {
+            Tweet::ImageTweet {
+                tweet: String::new(),
+                image_path: String::new(),
+            }
+        } else {
+            Tweet::Tweet(String::new())
+        };
+        Proposal {
+            tweet,
+            points,
+            created_by_id,
+            created_at,
+            reports: HashSet::new(),
+            rounds_survived: 0,
+            report_reasons: HashMap::new(),
+            moderation_status: ModerationStatus::Visible,
+            weighted_reports: None,
+            judged_reports: HashSet::new(),
+            nr_of_upvotes,
+            nr_of_downvotes,
+        }
+    }
+
+    #[test]
+    fn pages_sorted_and_filtered_proposals() {
+        let proposals = vec![
+            (0, proposal(1, 3, 10, false, (4, 3))),
+            (1, proposal(2, 5, 20, true, (5, 0))),
+            (2, proposal(1, 5, 30, true, (0, 0))),
+        ];
+        let mut index = ProposalIndex::build(proposals.into_iter());
+        let caller = Principal::anonymous();
+        let page = |index: &ProposalIndex, sort, filter: &ProposalFilter| {
+            index.page(sort, filter, &caller, &HashSet::from([0]), 0, 10)
+        };
+        let no_filter = ProposalFilter::default();
+
+        assert_eq!(page(&index, ProposalSort::Top, &no_filter), vec![1, 2, 0]);
+        assert_eq!(page(&index, ProposalSort::New, &no_filter), vec![2, 1, 0]);
+        assert_eq!(page(&index, ProposalSort::Controversial, &no_filter)[0], 0);
+        assert_eq!(
+            index.page(
+                ProposalSort::Top,
+                &no_filter,
+                &caller,
+                &HashSet::new(),
+                1,
+                1
+            ),
+            vec![2]
+        );
+
+        let by_author = ProposalFilter {
+            created_by: Some(1),
+            media_only: true,
+            ..Default::default()
+        };
+        assert_eq!(page(&index, ProposalSort::Top, &by_author), vec![2]);
+        let voted = ProposalFilter {
+            voted_by_me: true,
+            ..Default::default()
+        };
+        assert_eq!(page(&index, ProposalSort::Top, &voted), vec![0]);
+
+        index.record_report(1, caller);
+        index.record_vote(0, &proposal(1, 8, 10, false, (5, 3)));
+        let reported = ProposalFilter {
+            reported_by_me: true,
+            ..Default::default()
+        };
+        assert_eq!(page(&index, ProposalSort::Top, &reported), vec![1]);
+        assert_eq!(page(&index, ProposalSort::Top, &no_filter), vec![0, 1, 2]);
+
+        index.remove(1);
+        assert!(page(&index, ProposalSort::Top, &reported).is_empty());
+        assert_eq!(page(&index, ProposalSort::Hot, &no_filter), vec![0, 2]);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use candid::CandidType;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{decode, encode, get_earnings_memory, Memory},
+    RewardToken,
+};
+
+/// How much of the voters' share a voter gets, based on what they upvoted this round.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RewardTier {
+    // upvoted one of the winning proposals
+    Winner,
+    // upvoted one of the best ranked proposals, but not the winner
+    TopProposal,
+    // upvoted only proposals outside the top
+    Upvoted,
+}
+
+// relative weights of the reward tiers, voters that only downvoted get nothing
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct RewardCurve {
+    pub winner_weight: u32,
+    // number of best ranked proposals, including the winner, that count as top proposals
+    pub top_proposals: u32,
+    pub top_proposal_weight: u32,
+    pub upvoted_weight: u32,
+}
+
+impl Default for RewardCurve {
+    fn default() -> Self {
+        RewardCurve {
+            winner_weight: 3,
+            top_proposals: 5,
+            top_proposal_weight: 2,
+            upvoted_weight: 1,
+        }
+    }
+}
+
+impl RewardCurve {
+    pub fn weight(&self, tier: RewardTier) -> u64 {
+        let weight = match tier {
+            RewardTier::Winner => self.winner_weight,
+            RewardTier::TopProposal => self.top_proposal_weight,
+            RewardTier::Upvoted => self.upvoted_weight,
+        };
+        u64::from(weight)
+    }
+}
+
+/// The best tier reached by a voter's upvotes, `None` if they did not upvote anything.
+pub fn reward_tier(
+    upvotes: &HashSet<u32>,
+    winning_proposal_ids: &[u32],
+    top_proposal_ids: &[u32],
+) -> Option<RewardTier> {
+    if winning_proposal_ids.iter().any(|id| upvotes.contains(id)) {
+        Some(RewardTier::Winner)
+    } else if top_proposal_ids.iter().any(|id| upvotes.contains(id)) {
+        Some(RewardTier::TopProposal)
+    } else if !upvotes.is_empty() {
+        Some(RewardTier::Upvoted)
+    } else {
+        None
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy)]
+pub enum CreditReason {
+    WinningProposal,
+    Vote(RewardTier),
+    // upvoted without earning tokens, the user may not receive rewards
+    Participation,
+}
+
+/// What a user was credited in a round and why.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Credit {
+    pub user_id: u32,
+    pub e8s: u64,
+    pub karma: i32,
+    pub reason: CreditReason,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Earning {
+    pub round_id: u32,
+    pub concluded_at: u64,
+    pub token: RewardToken,
+    pub e8s: u64,
+    pub karma: i32,
+    pub reason: CreditReason,
+}
+
+impl Storable for Earning {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Credits of every round by user id, round id and position in the round's credits, so
+/// the earnings of a user are a single range.
+pub struct Earnings {
+    earnings: StableBTreeMap<(u32, u32, u32), Earning, Memory>,
+}
+
+impl Earnings {
+    pub fn new() -> Self {
+        Self {
+            earnings: StableBTreeMap::init(get_earnings_memory()),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        round_id: u32,
+        concluded_at: u64,
+        token: RewardToken,
+        credits: &[Credit],
+    ) {
+        for (index, credit) in credits.iter().enumerate() {
+            self.earnings.insert(
+                (credit.user_id, round_id, index as u32),
+                Earning {
+                    round_id,
+                    concluded_at,
+                    token,
+                    e8s: credit.e8s,
+                    karma: credit.karma,
+                    reason: credit.reason,
+                },
+            );
+        }
+    }
+
+    /// Earnings of a user, newest round first.
+    pub fn get(&self, user_id: u32, offset: usize, limit: usize) -> Vec<Earning> {
+        self.earnings
+            .range((user_id, 0, 0)..=(user_id, u32::MAX, u32::MAX))
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .map(|(_, earning)| earning)
+            .collect()
+    }
+}
+
+/// How the reward of a round is divided, every e8 of the budget ends up in exactly one field.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RewardSplit {
+    pub author_e8s: u64,
+    // reward of each voter, in the order of the weights they were split by
+    pub voter_e8s: Vec<u64>,
+    // what is left after rounding the voter shares down, carried over to the next round
+    pub remainder_e8s: u64,
+}
+
+/// Gives `author_percent` of the budget to the author and divides the rest among the voters
+/// in proportion to their weights, rounding down. Without any weight the whole voter share
+/// is the remainder.
+pub fn split_reward(budget_e8s: u64, author_percent: u8, voter_weights: &[u64]) -> RewardSplit {
+    let author_percent = u128::from(author_percent.min(100));
+    let author_e8s = (u128::from(budget_e8s) * author_percent / 100) as u64;
+    let voters_e8s = budget_e8s - author_e8s;
+
+    let total_weight = voter_weights
+        .iter()
+        .map(|&weight| u128::from(weight))
+        .sum::<u128>();
+    let voter_e8s = voter_weights
+        .iter()
+        .map(|&weight| {
+            (u128::from(voters_e8s) * u128::from(weight))
+                .checked_div(total_weight)
+                .unwrap_or_default() as u64
+        })
+        .collect::<Vec<_>>();
+    RewardSplit {
+        author_e8s,
+        remainder_e8s: voters_e8s - voter_e8s.iter().sum::<u64>(),
+        voter_e8s,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn splits_default_reward() {
+        let split = split_reward(100_000_000, 30, &[1, 1, 1]);
+        assert_eq!(split.author_e8s, 30_000_000);
+        assert_eq!(split.voter_e8s, vec![23_333_333; 3]);
+        assert_eq!(split.remainder_e8s, 1);
+    }
+
+    #[test]
+    fn keeps_voter_share_without_voters() {
+        let split = split_reward(1_000, 30, &[]);
+        assert_eq!(split.author_e8s, 300);
+        assert!(split.voter_e8s.is_empty());
+        assert_eq!(split.remainder_e8s, 700);
+    }
+
+    #[test]
+    fn splits_by_weight() {
+        let split = split_reward(1_000, 0, &[3, 1, 0]);
+        assert_eq!(split.voter_e8s, vec![750, 250, 0]);
+        assert_eq!(split.remainder_e8s, 0);
+    }
+
+    #[test]
+    fn ranks_voters_by_their_best_upvote() {
+        let top = [1, 2];
+        assert_eq!(
+            reward_tier(&HashSet::from([1, 2]), &[1], &top),
+            Some(RewardTier::Winner)
+        );
+        assert_eq!(
+            reward_tier(&HashSet::from([2, 7]), &[1], &top),
+            Some(RewardTier::TopProposal)
+        );
+        assert_eq!(
+            reward_tier(&HashSet::from([7]), &[1], &top),
+            Some(RewardTier::Upvoted)
+        );
+        assert_eq!(reward_tier(&HashSet::new(), &[1], &top), None);
+    }
+
+    proptest! {
+        #[test]
+        fn split_adds_up_to_budget(
+            budget_e8s in any::<u64>(),
+            author_percent in any::<u8>(),
+            voter_weights in prop::collection::vec(0..1_000u64, 0..200),
+        ) {
+            let split = split_reward(budget_e8s, author_percent, &voter_weights);
+            let total = u128::from(split.author_e8s)
+                + split.voter_e8s.iter().map(|&e8s| u128::from(e8s)).sum::<u128>()
+                + u128::from(split.remainder_e8s);
+            prop_assert_eq!(total, u128::from(budget_e8s));
+        }
+
+        #[test]
+        fn remainder_is_less_than_an_e8_per_voter(
+            budget_e8s in any::<u64>(),
+            author_percent in 0..=100u8,
+            voter_weights in prop::collection::vec(1..1_000u64, 1..200),
+        ) {
+            let split = split_reward(budget_e8s, author_percent, &voter_weights);
+            prop_assert!(split.remainder_e8s < voter_weights.len() as u64);
+            prop_assert!(u128::from(split.author_e8s) * 100
+                <= u128::from(budget_e8s) * u128::from(author_percent));
+        }
+
+        #[test]
+        fn higher_weights_never_get_less(
+            budget_e8s in any::<u64>(),
+            voter_weights in prop::collection::vec(0..1_000u64, 2..50),
+        ) {
+            let split = split_reward(budget_e8s, 30, &voter_weights);
+            for (i, j) in (0..voter_weights.len()).zip(1..voter_weights.len()) {
+                if voter_weights[i] >= voter_weights[j] {
+                    prop_assert!(split.voter_e8s[i] >= split.voter_e8s[j]);
+                } else {
+                    prop_assert!(split.voter_e8s[i] <= split.voter_e8s[j]);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::{HashMap, HashSet};
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    // admins pass every role check and are the only ones that can grant or revoke roles
+    Admin,
+    Moderator,
+    TweetPoster,
+    UserCreator,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct Roles {
+    pub roles: HashMap<Principal, HashSet<Role>>,
+}
+
+impl Roles {
+    pub fn new() -> Self {
+        Self {
+            roles: HashMap::new(),
+        }
+    }
+
+    pub fn with_admins(admins: Vec<Principal>) -> Self {
+        let mut roles = Self::new();
+        for admin in admins {
+            roles.grant(admin, Role::Admin);
+        }
+        roles
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roles.is_empty()
+    }
+
+    pub fn has_role(&self, principal: &Principal, role: Role) -> bool {
+        self.roles
+            .get(principal)
+            .map(|roles| roles.contains(&Role::Admin) || roles.contains(&role))
+            .unwrap_or(false)
+    }
+
+    pub fn grant(&mut self, principal: Principal, role: Role) {
+        self.roles.entry(principal).or_default().insert(role);
+    }
+
+    pub fn revoke(&mut self, principal: &Principal, role: Role) {
+        if let Some(roles) = self.roles.get_mut(principal) {
+            roles.remove(&role);
+            if roles.is_empty() {
+                self.roles.remove(principal);
+            }
+        }
+    }
+
+    pub fn nr_of_admins(&self) -> usize {
+        self.roles
+            .values()
+            .filter(|roles| roles.contains(&Role::Admin))
+            .count()
+    }
+
+    pub fn get_roles(&self) -> Vec<(Principal, Vec<Role>)> {
+        self.roles
+            .iter()
+            .map(|(principal, roles)| (*principal, roles.iter().copied().collect()))
+            .collect()
+    }
+}
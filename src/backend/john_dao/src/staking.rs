@@ -0,0 +1,320 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    icrc1::{Account, Subaccount},
+    memory::{decode, encode, get_stakes_memory, Memory},
+    payments::{TransferOutcome, RETRY_AFTER_NANOS},
+    Error,
+};
+
+// first byte of every staking subaccount, keeps them apart from any other subaccount of the canister
+const STAKING_SUBACCOUNT_TAG: u8 = 1;
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct DissolvingStake {
+    pub amount_e8s: u64,
+    pub dissolves_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct Stake {
+    pub staked_e8s: u64,
+    pub dissolving: Vec<DissolvingStake>,
+    // dissolved tokens that are currently being transferred back to the user
+    pub withdrawing_e8s: u64,
+    // transfer of `withdrawing_e8s`, retried with the same arguments until the ledger
+    // either executed or rejected it
+    #[serde(default)]
+    pub withdrawal: Option<PendingWithdrawal>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct PendingWithdrawal {
+    pub to: Account,
+    pub fee_e8s: u64,
+    pub created_at_time: u64,
+    pub last_attempt: u64,
+}
+
+impl Stake {
+    fn dissolving_e8s(&self) -> u64 {
+        self.dissolving.iter().map(|stake| stake.amount_e8s).sum()
+    }
+}
+
+impl Storable for Stake {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub struct UnstakeWithdrawal {
+    pub user_id: u32,
+    // amount taken from the stake, the user receives it minus the fee
+    pub amount_e8s: u64,
+    pub fee_e8s: u64,
+    pub from_subaccount: Subaccount,
+    pub to: Account,
+    pub created_at_time: u64,
+}
+
+impl UnstakeWithdrawal {
+    /// Memo of the transfer, the same for every attempt so the ledger deduplicates retries.
+    pub fn memo(&self) -> Vec<u8> {
+        self.created_at_time.to_be_bytes().to_vec()
+    }
+}
+
+/// Tokens staked by users, held by the canister in a subaccount per user.
+pub struct Stakes {
+    stakes: StableBTreeMap<u32, Stake, Memory>,
+}
+
+impl Stakes {
+    pub fn new() -> Self {
+        Self {
+            stakes: StableBTreeMap::init(get_stakes_memory()),
+        }
+    }
+
+    pub fn staking_subaccount(user_id: u32) -> Subaccount {
+        let mut subaccount = [0; 32];
+        subaccount[0] = STAKING_SUBACCOUNT_TAG;
+        subaccount[28..].copy_from_slice(&user_id.to_be_bytes());
+        subaccount
+    }
+
+    pub fn staking_account(canister_id: Principal, user_id: u32) -> Account {
+        Account {
+            owner: canister_id,
+            subaccount: Some(Self::staking_subaccount(user_id)),
+        }
+    }
+
+    pub fn get(&self, user_id: u32) -> Stake {
+        self.stakes.get(&user_id).unwrap_or_default()
+    }
+
+    pub fn staked_e8s(&self, user_id: u32) -> u64 {
+        self.get(user_id).staked_e8s
+    }
+
+    /// Credits everything in the staking subaccount that is not dissolving or being
+    /// withdrawn as active stake.
+    pub fn refresh(&mut self, user_id: u32, balance_e8s: u64) -> Stake {
+        let mut stake = self.get(user_id);
+        stake.staked_e8s =
+            balance_e8s.saturating_sub(stake.dissolving_e8s() + stake.withdrawing_e8s);
+        self.stakes.insert(user_id, stake.clone());
+        stake
+    }
+
+    pub fn unstake(
+        &mut self,
+        user_id: u32,
+        amount_e8s: u64,
+        dissolves_at: u64,
+    ) -> Result<Stake, Error> {
+        let mut stake = self.get(user_id);
+        if amount_e8s == 0 || amount_e8s > stake.staked_e8s {
+            return Err(Error::InsufficientStake);
+        }
+        stake.staked_e8s -= amount_e8s;
+        stake.dissolving.push(DissolvingStake {
+            amount_e8s,
+            dissolves_at,
+        });
+        self.stakes.insert(user_id, stake.clone());
+        Ok(stake)
+    }
+
+    /// Takes all dissolved stake out of the stake and records the transfer that returns it
+    /// to `to`. A withdrawal whose outcome is still unknown is retried with its original
+    /// arguments instead.
+    pub fn stage_withdrawal(
+        &mut self,
+        user_id: u32,
+        to: Account,
+        fee_e8s: u64,
+        now: u64,
+    ) -> Result<UnstakeWithdrawal, Error> {
+        let mut stake = self.get(user_id);
+        if let Some(pending) = &mut stake.withdrawal {
+            if now < pending.last_attempt + RETRY_AFTER_NANOS {
+                return Err(Error::PaymentInProgress);
+            }
+            pending.last_attempt = now;
+            let withdrawal = UnstakeWithdrawal {
+                user_id,
+                amount_e8s: stake.withdrawing_e8s,
+                fee_e8s: pending.fee_e8s,
+                from_subaccount: Self::staking_subaccount(user_id),
+                to: pending.to,
+                created_at_time: pending.created_at_time,
+            };
+            self.stakes.insert(user_id, stake);
+            return Ok(withdrawal);
+        }
+        if stake.withdrawing_e8s > 0 {
+            return Err(Error::PaymentInProgress);
+        }
+
+        let (dissolved, dissolving) = stake
+            .dissolving
+            .into_iter()
+            .partition::<Vec<_>, _>(|stake| stake.dissolves_at <= now);
+        let amount_e8s = dissolved.iter().map(|stake| stake.amount_e8s).sum::<u64>();
+        if amount_e8s == 0 {
+            return Err(Error::NoDissolvedStake);
+        }
+        if amount_e8s <= fee_e8s {
+            return Err(Error::InsufficientStake);
+        }
+        stake.dissolving = dissolving;
+        stake.withdrawing_e8s = amount_e8s;
+        stake.withdrawal = Some(PendingWithdrawal {
+            to,
+            fee_e8s,
+            created_at_time: now,
+            last_attempt: now,
+        });
+        self.stakes.insert(user_id, stake);
+        Ok(UnstakeWithdrawal {
+            user_id,
+            amount_e8s,
+            fee_e8s,
+            from_subaccount: Self::staking_subaccount(user_id),
+            to,
+            created_at_time: now,
+        })
+    }
+
+    /// Settles a withdrawal once the ledger executed or rejected it, a failed transfer
+    /// returns the amount to the dissolved stake. Unknown outcomes stay pending.
+    pub fn finish_withdrawal(
+        &mut self,
+        withdrawal: &UnstakeWithdrawal,
+        outcome: &TransferOutcome,
+        now: u64,
+    ) {
+        let mut stake = self.get(withdrawal.user_id);
+        match outcome {
+            TransferOutcome::Paid(_) => {}
+            TransferOutcome::Failed(_) => stake.dissolving.push(DissolvingStake {
+                amount_e8s: withdrawal.amount_e8s,
+                dissolves_at: now,
+            }),
+            TransferOutcome::Unknown(_) => return,
+        }
+        stake.withdrawing_e8s = 0;
+        stake.withdrawal = None;
+        self.stakes.insert(withdrawal.user_id, stake);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account() -> Account {
+        Account {
+            owner: Principal::anonymous(),
+            subaccount: None,
+        }
+    }
+
+    #[test]
+    fn stakes_and_unstakes_the_subaccount_balance() {
+        let mut stakes = Stakes::new();
+        assert_eq!(stakes.refresh(1, 1_000).staked_e8s, 1_000);
+        assert!(matches!(
+            stakes.unstake(1, 1_001, 50),
+            Err(Error::InsufficientStake)
+        ));
+        assert!(matches!(
+            stakes.unstake(1, 0, 50),
+            Err(Error::InsufficientStake)
+        ));
+
+        let stake = stakes.unstake(1, 400, 50).ok().unwrap();
+        assert_eq!(stake.staked_e8s, 600);
+        assert_eq!(stake.dissolving_e8s(), 400);
+        // dissolving tokens are still in the subaccount but do not count as stake
+        assert_eq!(stakes.refresh(1, 1_100).staked_e8s, 700);
+        assert_eq!(stakes.staked_e8s(2), 0);
+    }
+
+    #[test]
+    fn withdraws_dissolved_stake_once() {
+        let mut stakes = Stakes::new();
+        stakes.refresh(1, 1_000);
+        stakes.unstake(1, 300, 50).ok().unwrap();
+        stakes.unstake(1, 200, 80).ok().unwrap();
+
+        assert!(matches!(
+            stakes.stage_withdrawal(1, account(), 10, 40),
+            Err(Error::NoDissolvedStake)
+        ));
+        assert!(matches!(
+            stakes.stage_withdrawal(1, account(), 300, 60),
+            Err(Error::InsufficientStake)
+        ));
+        let withdrawal = stakes.stage_withdrawal(1, account(), 10, 60).ok().unwrap();
+        assert_eq!(withdrawal.amount_e8s, 300);
+        assert_eq!(withdrawal.created_at_time, 60);
+        assert_eq!(stakes.get(1).withdrawing_e8s, 300);
+        assert_eq!(stakes.get(1).dissolving_e8s(), 200);
+        // withdrawing tokens are not staked again before the transfer settles
+        assert_eq!(stakes.refresh(1, 1_000).staked_e8s, 500);
+
+        // an unknown outcome stays pending, a retry reuses the original arguments
+        let unknown = TransferOutcome::Unknown("timeout".to_string());
+        stakes.finish_withdrawal(&withdrawal, &unknown, 70);
+        assert!(matches!(
+            stakes.stage_withdrawal(1, account(), 20, 100),
+            Err(Error::PaymentInProgress)
+        ));
+        let retry = stakes
+            .stage_withdrawal(1, account(), 20, 60 + RETRY_AFTER_NANOS)
+            .ok()
+            .unwrap();
+        assert_eq!(retry.amount_e8s, 300);
+        assert_eq!(retry.fee_e8s, 10);
+        assert_eq!(retry.memo(), withdrawal.memo());
+
+        stakes.finish_withdrawal(&retry, &TransferOutcome::Paid(7), 200);
+        let stake = stakes.get(1);
+        assert_eq!(stake.withdrawing_e8s, 0);
+        assert!(stake.withdrawal.is_none());
+        assert_eq!(stake.dissolving_e8s(), 200);
+    }
+
+    #[test]
+    fn returns_rejected_withdrawals_to_the_dissolved_stake() {
+        let mut stakes = Stakes::new();
+        stakes.refresh(1, 1_000);
+        stakes.unstake(1, 300, 50).ok().unwrap();
+        let withdrawal = stakes.stage_withdrawal(1, account(), 10, 60).ok().unwrap();
+
+        let failed = TransferOutcome::Failed("insufficient funds".to_string());
+        stakes.finish_withdrawal(&withdrawal, &failed, 70);
+        let stake = stakes.get(1);
+        assert_eq!(stake.withdrawing_e8s, 0);
+        assert!(stake.withdrawal.is_none());
+        assert_eq!(stake.dissolving_e8s(), 300);
+
+        let withdrawal = stakes.stage_withdrawal(1, account(), 10, 80).ok().unwrap();
+        assert_eq!(withdrawal.amount_e8s, 300);
+        assert_eq!(withdrawal.created_at_time, 80);
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::{users::User, RewardToken};
+
+pub const BALANCE_REFRESH_INTERVAL_SECONDS: u64 = 3600;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy)]
+pub struct LedgerBalance {
+    pub balance_e8s: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RewardStatus {
+    // the treasury covers the full reward of the next round
+    Full,
+    // the treasury only covers part of the reward of the next round
+    Scaled,
+    // the treasury covers nothing or its balance is not known yet
+    Paused,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct TreasuryStatus {
+    pub token: RewardToken,
+    pub balance: Option<LedgerBalance>,
+    pub withdrawable_e8s: u64,
+    pub pending_payments_e8s: u64,
+    pub reward_per_round_e8s: u64,
+    pub reward_carry_over_e8s: u64,
+    pub next_round_reward_e8s: u64,
+    pub reward_status: RewardStatus,
+}
+
+/// Keeps what the canister owes its users next to what it owns on each ledger, so rewards
+/// are never credited beyond what the canister can pay out.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Treasury {
+    // sum of the withdrawable balances of all users
+    withdrawable_e8s: HashMap<RewardToken, u64>,
+    // last known balance of the canister's default account on each ledger
+    balances: HashMap<RewardToken, LedgerBalance>,
+}
+
+impl Treasury {
+    /// Rebuilds the withdrawable totals from the users, the running totals are only kept
+    /// up to date by the canister's own credits and withdrawals.
+    pub fn recompute_withdrawable(&mut self, users: &[User]) {
+        self.withdrawable_e8s.clear();
+        for user in users {
+            *self.withdrawable_e8s.entry(RewardToken::Icp).or_default() += user.withdrawable_e8s;
+            for (ledger_canister_id, amount) in &user.withdrawable_tokens {
+                let token = RewardToken::Icrc1 {
+                    ledger_canister_id: *ledger_canister_id,
+                };
+                *self.withdrawable_e8s.entry(token).or_default() += amount;
+            }
+        }
+    }
+
+    pub fn withdrawable_e8s(&self, token: &RewardToken) -> u64 {
+        self.withdrawable_e8s
+            .get(token)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn credit(&mut self, token: &RewardToken, amount: u64) {
+        *self.withdrawable_e8s.entry(*token).or_default() += amount;
+    }
+
+    pub fn debit(&mut self, token: &RewardToken, amount: u64) {
+        let withdrawable_e8s = self.withdrawable_e8s.entry(*token).or_default();
+        *withdrawable_e8s = withdrawable_e8s.saturating_sub(amount);
+    }
+
+    pub fn balance(&self, token: &RewardToken) -> Option<LedgerBalance> {
+        self.balances.get(token).copied()
+    }
+
+    pub fn set_balance(&mut self, token: RewardToken, balance_e8s: u64, now: u64) {
+        self.balances.insert(
+            token,
+            LedgerBalance {
+                balance_e8s,
+                updated_at: now,
+            },
+        );
+    }
+
+    pub fn forget_balance(&mut self, token: &RewardToken) {
+        self.balances.remove(token);
+    }
+
+    /// Raises the last known balance by a deposit that happened after it was fetched.
+    pub fn record_deposit(&mut self, token: &RewardToken, amount: u64, time: u64) {
+        if let Some(balance) = self.balances.get_mut(token) {
+            if time > balance.updated_at {
+                balance.balance_e8s += amount;
+            }
+        }
+    }
+
+    /// Lowers the last known balance by a payout so it stays accurate until the next refresh.
+    pub fn record_payout(&mut self, token: &RewardToken, amount: u64) {
+        if let Some(balance) = self.balances.get_mut(token) {
+            balance.balance_e8s = balance.balance_e8s.saturating_sub(amount);
+        }
+    }
+
+    /// Part of `reward_e8s` that the treasury can cover on top of everything it already owes.
+    pub fn coverable_reward(
+        &self,
+        token: &RewardToken,
+        reward_e8s: u64,
+        pending_payments_e8s: u64,
+    ) -> u64 {
+        let Some(balance) = self.balance(token) else {
+            return 0;
+        };
+        let liabilities = self.withdrawable_e8s(token) + pending_payments_e8s;
+        balance
+            .balance_e8s
+            .saturating_sub(liabilities)
+            .min(reward_e8s)
+    }
+}
+
+pub fn reward_status(reward_e8s: u64, coverable_e8s: u64) -> RewardStatus {
+    if coverable_e8s >= reward_e8s {
+        RewardStatus::Full
+    } else if coverable_e8s > 0 {
+        RewardStatus::Scaled
+    } else {
+        RewardStatus::Paused
+    }
+}
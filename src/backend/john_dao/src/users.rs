@@ -1,11 +1,22 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
 
 use candid::{CandidType, Principal};
 use ic_cdk::api::time;
 use ic_ledger_types::AccountIdentifier;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
 use serde::{Deserialize, Serialize};
 
-use crate::Error;
+use crate::{
+    icrc1::Account,
+    memory::{
+        decode, encode, from_principal, get_user_principals_memory, get_users_memory, to_principal,
+        Memory, StablePrincipal,
+    },
+    Error, RewardToken,
+};
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
 pub enum LoginMethod {
@@ -14,7 +25,7 @@ pub enum LoginMethod {
     Other(String),
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum VerificationStatus {
     Unverified,
     Verified,
@@ -37,76 +48,234 @@ pub struct User {
     pub new_auth: Option<(Principal, LoginMethod)>,
     pub karma: i32,
     pub withdrawable_e8s: u64,
+    // rewards paid in ICRC-1 tokens, by ledger canister id
+    #[serde(default)]
+    pub withdrawable_tokens: HashMap<Principal, u64>,
     pub deposit_address: Option<AccountIdentifier>,
+    #[serde(default)]
+    pub icrc1_account: Option<Account>,
     pub payment_in_progress: bool,
-    pub created_proposal: Option<u32>, //this one seems to be useless oops
     pub upvotes: HashSet<u32>,
     pub downvotes: HashSet<u32>,
+    // voting power used for each vote this round, votes without an entry have a weight of 1
+    #[serde(default)]
+    pub vote_weights: HashMap<u32, i32>,
+    // quadratic voting credits spent this round
+    #[serde(default)]
+    pub vote_credits_used: u64,
+    // round rewards that were not withdrawn yet, oldest first
+    #[serde(default)]
+    pub unpaid_credits: Vec<UnpaidCredit>,
     pub verification_status: VerificationStatus,
     pub last_updated: u64,
     pub nr_posts_this_round: u32,
+    #[serde(default)]
+    pub nr_reports_this_round: u32,
+    // reports on proposals that moderators removed and approved
+    #[serde(default)]
+    pub reports_upheld: u32,
+    #[serde(default)]
+    pub reports_rejected: u32,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct UnpaidCredit {
+    pub round_id: u32,
+    pub token: RewardToken,
+    pub e8s: u64,
+}
+
+/// What a single vote counts for and, under quadratic voting, the round budget it is paid
+/// from. A vote of weight `w` costs `w * w` credits.
+pub struct VoteWeight {
+    pub weight: i32,
+    pub credit_budget: Option<u64>,
+}
+
+fn vote_cost(weight: i32) -> u64 {
+    let weight = weight.unsigned_abs() as u64;
+    weight * weight
+}
+
+fn refund_vote_credits(user: &mut User, weight: i32, vote_weight: &VoteWeight) {
+    if vote_weight.credit_budget.is_some() {
+        user.vote_credits_used = user.vote_credits_used.saturating_sub(vote_cost(weight));
+    }
+}
+
+/// Credits `user` has used after a new vote, refunding the vote it replaces. `None` if
+/// votes cost no credits.
+fn credits_after_vote(
+    user: &User,
+    replaced_weight: Option<i32>,
+    vote_weight: &VoteWeight,
+) -> Result<Option<u64>, Error> {
+    let Some(credit_budget) = vote_weight.credit_budget else {
+        return Ok(None);
+    };
+    let refund = replaced_weight.map(vote_cost).unwrap_or_default();
+    let other_votes_credits = user.vote_credits_used.saturating_sub(refund);
+    let credits_used = other_votes_credits + vote_cost(vote_weight.weight);
+    if credits_used > credit_budget {
+        return Err(Error::NotEnoughVoteCredits {
+            remaining: credit_budget.saturating_sub(other_votes_credits),
+        });
+    }
+    Ok(Some(credits_used))
+}
+
+/// Charges the credits for a new vote, refunding the vote it replaces.
+fn pay_vote_credits(
+    user: &mut User,
+    replaced_weight: Option<i32>,
+    vote_weight: &VoteWeight,
+) -> Result<(), Error> {
+    if let Some(credits_used) = credits_after_vote(user, replaced_weight, vote_weight)? {
+        user.vote_credits_used = credits_used;
+    }
+    Ok(())
+}
+
+/// Fails if `user` can not afford a new vote of `vote_weight`.
+pub fn check_vote_credits(user: &User, vote_weight: &VoteWeight) -> Result<(), Error> {
+    credits_after_vote(user, None, vote_weight).map(|_| ())
+}
+
+impl User {
+    /// Marks `amount` e8s of the oldest unpaid credits in `token` as paid and returns the
+    /// rounds they were earned in.
+    pub fn pay_credits(&mut self, token: &RewardToken, mut amount: u64) -> Vec<u32> {
+        let mut round_ids = Vec::new();
+        for credit in self
+            .unpaid_credits
+            .iter_mut()
+            .filter(|credit| credit.token == *token)
+        {
+            if amount == 0 {
+                break;
+            }
+            let paid = credit.e8s.min(amount);
+            credit.e8s -= paid;
+            amount -= paid;
+            round_ids.push(credit.round_id);
+        }
+        self.unpaid_credits.retain(|credit| credit.e8s > 0);
+        round_ids
+    }
+
+    pub fn withdrawable_mut(&mut self, token: &RewardToken) -> &mut u64 {
+        match token {
+            RewardToken::Icp => &mut self.withdrawable_e8s,
+            RewardToken::Icrc1 { ledger_canister_id } => self
+                .withdrawable_tokens
+                .entry(*ledger_canister_id)
+                .or_default(),
+        }
+    }
+}
+
+impl Storable for User {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 pub struct Users {
-    pub users: HashMap<Principal, User>,
-    pub user_principals: HashMap<u32, Principal>,
+    users: StableBTreeMap<StablePrincipal, User, Memory>,
+    user_principals: StableBTreeMap<u32, StablePrincipal, Memory>,
 }
 
 impl Users {
     pub fn new() -> Self {
         Self {
-            users: HashMap::new(),
-            user_principals: HashMap::new(),
+            users: StableBTreeMap::init(get_users_memory()),
+            user_principals: StableBTreeMap::init(get_user_principals_memory()),
         }
     }
 
-    pub fn get(&self, principal: &Principal) -> Option<&User> {
-        self.users.get(principal)
+    pub fn get(&self, principal: &Principal) -> Option<User> {
+        self.users.get(&to_principal(principal))
     }
 
-    pub fn get_mut(&mut self, principal: &Principal) -> Option<&mut User> {
-        self.users.get_mut(principal).map(|user| {
-            user.last_updated = time();
-            user
-        })
+    pub fn get_by_id(&self, id: u32) -> Option<User> {
+        let principal = self.user_principals.get(&id)?;
+        self.users.get(&principal)
     }
 
-    pub fn get_by_id(&mut self, id: u32) -> Option<&mut User> {
-        let principal = *self.user_principals.get(&id)?;
-        self.users.get_mut(&principal)
+    /// Applies `f` to the user and writes the result back to stable memory.
+    pub fn update<R>(
+        &mut self,
+        principal: &Principal,
+        f: impl FnOnce(&mut User) -> R,
+    ) -> Option<R> {
+        let key = to_principal(principal);
+        let mut user = self.users.get(&key)?;
+        user.last_updated = time();
+        let result = f(&mut user);
+        self.users.insert(key, user);
+        Some(result)
+    }
+
+    pub fn update_by_id<R>(&mut self, id: u32, f: impl FnOnce(&mut User) -> R) -> Option<R> {
+        let key = self.user_principals.get(&id)?;
+        let mut user = self.users.get(&key)?;
+        let result = f(&mut user);
+        self.users.insert(key, user);
+        Some(result)
+    }
+
+    /// Decodes and writes back every user, dropping fields that no longer exist on `User`.
+    pub fn reencode_all(&mut self) {
+        let principals = self
+            .users
+            .iter()
+            .map(|(principal, _)| principal)
+            .collect::<Vec<_>>();
+        for principal in principals {
+            let user = self.users.get(&principal).unwrap();
+            self.users.insert(principal, user);
+        }
     }
 
     pub fn get_users(&self) -> Vec<User> {
-        self.users.values().cloned().collect()
+        self.users.iter().map(|(_, user)| user).collect()
     }
 
     pub fn get_user_range(&self, start: u32, end: u32) -> Vec<User> {
-        self.users
-            .values()
-            .cloned()
-            .filter(|user| user.id >= start && user.id <= end)
+        if start > end {
+            return Vec::new();
+        }
+        self.user_principals
+            .range(start..=end)
+            .filter_map(|(_, principal)| self.users.get(&principal))
             .collect()
     }
 
     pub fn get_users_by_karma(&self, nr_of_users: u32) -> Vec<User> {
-        let mut users = self.users.values().cloned().collect::<Vec<User>>();
-        users.sort_by(|a, b| b.karma.cmp(&a.karma));
+        let mut users = self.get_users();
+        users.sort_by_key(|user| std::cmp::Reverse(user.karma));
         users.truncate(nr_of_users as usize);
         users
     }
 
     pub fn insert(&mut self, principal: Principal, user: User) {
-        self.users.insert(principal, user.clone());
-        self.user_principals.insert(user.id, principal);
+        let key = to_principal(&principal);
+        self.user_principals.insert(user.id, key);
+        self.users.insert(key, user);
     }
 
     pub fn principal_in_use(&self, principal: &Principal) -> bool {
-        self.users.contains_key(principal)
+        self.users.contains_key(&to_principal(principal))
     }
 
     pub fn remove(&mut self, principal: &Principal) -> Option<User> {
-        let user = self.users.remove(principal);
+        let user = self.users.remove(&to_principal(principal));
         if let Some(user) = &user {
             self.user_principals.remove(&user.id);
         }
@@ -117,7 +286,7 @@ impl Users {
         let mut changed_users = Vec::new();
         for (principal, user) in self.users.iter() {
             if user.last_updated > time {
-                changed_users.push((*principal, user.clone()));
+                changed_users.push((from_principal(&principal), user));
             }
         }
         changed_users
@@ -127,27 +296,59 @@ impl Users {
         &mut self,
         upvoter: &Principal,
         proposal_id: &u32,
+        vote_weight: &VoteWeight,
     ) -> Result<i32, Error> {
-        let upvoting_user = self.get_mut(upvoter).ok_or(Error::UserDoesNotExist)?;
-        if upvoting_user.upvotes.contains(proposal_id) {
-            // cancel earlier vote if already upvoted
-            upvoting_user.upvotes.remove(proposal_id);
-            return Ok(-1);
-        }
-        if upvoting_user.downvotes.contains(proposal_id) {
-            upvoting_user.downvotes.remove(proposal_id);
-            upvoting_user.upvotes.insert(*proposal_id);
-            Ok(2)
-        } else {
+        self.update(upvoter, |upvoting_user| {
+            let previous_weight = upvoting_user
+                .vote_weights
+                .get(proposal_id)
+                .copied()
+                .unwrap_or(1);
+            if upvoting_user.upvotes.contains(proposal_id) {
+                // cancel earlier vote if already upvoted
+                upvoting_user.upvotes.remove(proposal_id);
+                upvoting_user.vote_weights.remove(proposal_id);
+                refund_vote_credits(upvoting_user, previous_weight, vote_weight);
+                return Ok(-previous_weight);
+            }
+            let switched = upvoting_user.downvotes.contains(proposal_id);
+            pay_vote_credits(
+                upvoting_user,
+                switched.then_some(previous_weight),
+                vote_weight,
+            )?;
+            upvoting_user
+                .vote_weights
+                .insert(*proposal_id, vote_weight.weight);
             upvoting_user.upvotes.insert(*proposal_id);
-            Ok(1)
-        }
+            if switched {
+                upvoting_user.downvotes.remove(proposal_id);
+                Ok(previous_weight + vote_weight.weight)
+            } else {
+                Ok(vote_weight.weight)
+            }
+        })
+        .ok_or(Error::UserDoesNotExist)?
     }
 
     pub fn reset_round(&mut self) {
-        for user in self.users.values_mut() {
+        let voted = self
+            .users
+            .iter()
+            .filter(|(_, user)| {
+                !user.upvotes.is_empty()
+                    || !user.downvotes.is_empty()
+                    || user.vote_credits_used > 0
+                    || user.nr_reports_this_round > 0
+            })
+            .collect::<Vec<_>>();
+        for (principal, mut user) in voted {
             user.upvotes.clear();
             user.downvotes.clear();
+            user.vote_weights.clear();
+            user.vote_credits_used = 0;
+            user.nr_reports_this_round = 0;
+            self.users.insert(principal, user);
         }
     }
 
@@ -155,26 +356,44 @@ impl Users {
         &mut self,
         caller: &Principal,
         proposal_id: &u32,
+        vote_weight: &VoteWeight,
     ) -> Result<i32, Error> {
-        let downvoting_user = self.get_mut(caller).ok_or(Error::UserDoesNotExist)?;
-        if downvoting_user.downvotes.contains(proposal_id) {
-            // cancel earlier vote if already downvoted
-            downvoting_user.downvotes.remove(proposal_id);
-            return Ok(1);
-        }
-        if downvoting_user.upvotes.contains(proposal_id) {
-            downvoting_user.upvotes.remove(proposal_id);
-            downvoting_user.downvotes.insert(*proposal_id);
-            Ok(-2)
-        } else {
+        self.update(caller, |downvoting_user| {
+            let previous_weight = downvoting_user
+                .vote_weights
+                .get(proposal_id)
+                .copied()
+                .unwrap_or(1);
+            if downvoting_user.downvotes.contains(proposal_id) {
+                // cancel earlier vote if already downvoted
+                downvoting_user.downvotes.remove(proposal_id);
+                downvoting_user.vote_weights.remove(proposal_id);
+                refund_vote_credits(downvoting_user, previous_weight, vote_weight);
+                return Ok(previous_weight);
+            }
+            let switched = downvoting_user.upvotes.contains(proposal_id);
+            pay_vote_credits(
+                downvoting_user,
+                switched.then_some(previous_weight),
+                vote_weight,
+            )?;
+            downvoting_user
+                .vote_weights
+                .insert(*proposal_id, vote_weight.weight);
             downvoting_user.downvotes.insert(*proposal_id);
-            Ok(-1)
-        }
+            if switched {
+                downvoting_user.upvotes.remove(proposal_id);
+                Ok(-(previous_weight + vote_weight.weight))
+            } else {
+                Ok(-vote_weight.weight)
+            }
+        })
+        .ok_or(Error::UserDoesNotExist)?
     }
 
     pub fn get_all_users_that_voted(&self) -> Vec<u32> {
         let mut users_that_voted = Vec::new();
-        for user in self.users.values() {
+        for (_, user) in self.users.iter() {
             if !user.upvotes.is_empty() || !user.downvotes.is_empty() {
                 users_that_voted.push(user.id);
             }